@@ -0,0 +1,44 @@
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    let commit = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|commit| commit.trim().to_string())
+        .unwrap_or_else(|| String::from("unknown"));
+    // Reproducible builds pin the timestamp.
+    let timestamp = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0)
+        });
+
+    println!("cargo:rustc-env=SOCKS5_SERVER_GIT_COMMIT={}", commit);
+    println!(
+        "cargo:rustc-env=SOCKS5_SERVER_BUILD_TIMESTAMP={}",
+        timestamp
+    );
+
+    // Rerun whenever the crate itself would be rebuilt, so the timestamp
+    // follows the source, and when the checked-out commit moves. The git
+    // paths are missing outside a checkout, and naming a missing path
+    // would make every build dirty.
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    for path in ["build.rs", "Cargo.toml", "src"] {
+        println!("cargo:rerun-if-changed={}", path);
+    }
+    for path in [".git/HEAD", ".git/refs", ".git/packed-refs"] {
+        if Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+}
@@ -0,0 +1,97 @@
+//! Measures upload throughput through the proxy on loopback with different
+//! socket buffer sizes.
+//!
+//!     cargo run --release --example buffer_bench [MIB]
+
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use socks5_server::{Config, Extensions};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+
+const CHUNK: usize = 64 * 1024;
+
+#[tokio::main]
+async fn main() {
+    let mib: usize = std::env::args()
+        .nth(1)
+        .map_or(1024, |arg| arg.parse().expect("MIB must be a number"));
+    let total = mib * 1024 * 1024;
+
+    for size in [
+        None,
+        Some(64 * 1024),
+        Some(256 * 1024),
+        Some(4 * 1024 * 1024),
+    ] {
+        let elapsed = run(size, total).await;
+        let label = size.map_or_else(
+            || String::from("OS default"),
+            |s| format!("{} KiB", s / 1024),
+        );
+        println!(
+            "{:>12}: {} MiB in {:.2?}, {:.0} MiB/s",
+            label,
+            mib,
+            elapsed,
+            mib as f64 / elapsed.as_secs_f64()
+        );
+    }
+}
+
+async fn run(buffer_size: Option<usize>, total: usize) -> std::time::Duration {
+    let config = Config {
+        send_buffer_size: buffer_size,
+        recv_buffer_size: buffer_size,
+        ..Config::default()
+    };
+    let listener = socks5_server::listen("127.0.0.1:0", &config).await.unwrap();
+    let proxy = listener.local_addr().unwrap();
+    tokio::spawn(socks5_server::serve_listener(
+        listener,
+        config,
+        Extensions::default(),
+    ));
+
+    let (sink, done) = sink(total).await;
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+    client.read_exact(&mut [0u8; 2]).await.unwrap();
+    let mut request = vec![0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1];
+    request.extend_from_slice(&sink.port().to_be_bytes());
+    client.write_all(&request).await.unwrap();
+    client.read_exact(&mut [0u8; 10]).await.unwrap();
+
+    let chunk = vec![0u8; CHUNK];
+    let start = Instant::now();
+    let mut sent = 0;
+    while sent < total {
+        let n = CHUNK.min(total - sent);
+        client.write_all(&chunk[..n]).await.unwrap();
+        sent += n;
+    }
+    done.await.unwrap();
+    start.elapsed()
+}
+
+/// Accepts one connection and signals once `total` bytes have arrived.
+async fn sink(total: usize) -> (SocketAddr, oneshot::Receiver<()>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = oneshot::channel();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = vec![0u8; CHUNK];
+        let mut received = 0;
+        while received < total {
+            match stream.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => received += n,
+            }
+        }
+        let _ = tx.send(());
+    });
+    (addr, rx)
+}
@@ -0,0 +1,51 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use log::warn;
+
+use crate::Shared;
+
+impl Shared {
+    /// Rewrites `ban_list_file` with the IPs currently in handshake-failure
+    /// cooldown, one per line as `<ip> <banned_at> <until>` in unix seconds.
+    /// The file is replaced atomically, and expired bans drop out the next
+    /// time it is written, so consumers should honour `until`.
+    pub(crate) fn export_bans(&self) {
+        let path = match &self.config.ban_list_file {
+            Some(path) => path,
+            None => return,
+        };
+
+        let _guard = self.ban_list_lock.lock().unwrap();
+        let now = Instant::now();
+        let wall = SystemTime::now();
+        let unix = |at: Instant| {
+            let at = if at > now {
+                wall + (at - now)
+            } else {
+                wall - (now - at)
+            };
+            at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+        };
+
+        let mut bans = self.handshake_failures.blocked();
+        bans.sort();
+        let mut out = String::from("# ip banned_at until\n");
+        for (ip, since, until) in bans {
+            let _ = writeln!(out, "{} {} {}", ip, unix(since), unix(until));
+        }
+
+        if let Err(e) = write_atomic(path, &out) {
+            warn!("Failed to write ban list {}: {}", path.display(), e);
+        }
+    }
+}
+
+fn write_atomic(path: &Path, contents: &str) -> std::io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path)
+}
@@ -0,0 +1,211 @@
+//! Best-effort protocol detection from the first bytes of a tunnel, for
+//! logging. Only looks at the data it is given, so a TLS ClientHello cut
+//! off before its server_name extension is reported without an SNI.
+
+use std::sync::Arc;
+
+use log::{debug, info};
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+use crate::{DialContext, EstablishedFuture, EstablishedHook, RelayStream};
+
+const HTTP_METHODS: &[&str] = &[
+    "GET", "POST", "PUT", "HEAD", "DELETE", "OPTIONS", "PATCH", "CONNECT", "TRACE",
+];
+
+/// Logs what protocol each tunnel carries, judged from the first data
+/// either side sends, then hands the streams on to `inner`. Nothing is
+/// consumed from either stream.
+pub(crate) struct ProtocolClassifier {
+    pub inner: Arc<dyn EstablishedHook>,
+}
+
+impl EstablishedHook for ProtocolClassifier {
+    fn on_established<'a>(
+        &'a self,
+        client: RelayStream<'a>,
+        upstream: RelayStream<'a>,
+        ctx: &'a DialContext,
+    ) -> EstablishedFuture<'a> {
+        Box::pin(async move {
+            let mut client = BufReader::new(client);
+            let mut upstream = BufReader::new(upstream);
+            let (side, data) = tokio::select! {
+                data = client.fill_buf() => ("client", data),
+                data = upstream.fill_buf() => ("upstream", data),
+            };
+            match data.ok().and_then(classify) {
+                Some(protocol) => info!("{}: Protocol {} from {}", ctx.peer_addr, protocol, side),
+                None => debug!("{}: Protocol not recognised", ctx.peer_addr),
+            }
+            self.inner
+                .on_established(Box::new(client), Box::new(upstream), ctx)
+                .await
+        })
+    }
+}
+
+/// Describes the protocol `data` starts with, such as `tls sni=example.com`,
+/// or `None` if it is not recognised.
+fn classify(data: &[u8]) -> Option<String> {
+    if data.starts_with(&[0x16, 0x03]) {
+        return Some(match sni(data) {
+            Some(name) => format!("tls sni={}", printable(name)),
+            None => String::from("tls"),
+        });
+    }
+    if data.starts_with(b"SSH-") {
+        let end = data.iter().position(|&b| b == b'\r' || b == b'\n');
+        let banner = &data[..end.unwrap_or(data.len()).min(255)];
+        return Some(format!("ssh banner={}", printable(banner)));
+    }
+
+    let method = HTTP_METHODS.iter().find(|method| {
+        data.starts_with(method.as_bytes()) && data.get(method.len()) == Some(&b' ')
+    })?;
+    let host = data
+        .split(|&b| b == b'\n')
+        .skip(1)
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+        .take_while(|line| !line.is_empty())
+        .find_map(|line| {
+            let (name, value) = line.split_at(line.iter().position(|&b| b == b':')?);
+            name.eq_ignore_ascii_case(b"host")
+                .then(|| value[1..].trim_ascii())
+        });
+    Some(match host {
+        Some(host) => format!("http method={} host={}", method, printable(host)),
+        None => format!("http method={}", method),
+    })
+}
+
+/// The server name from a ClientHello's server_name extension.
+fn sni(data: &[u8]) -> Option<&[u8]> {
+    let mut r = Reader(data);
+    r.skip(5)?; // record header
+    if r.u8()? != 0x01 {
+        return None;
+    }
+    r.skip(3 + 2 + 32)?; // handshake length, version, random
+    let session_id = r.u8()? as usize;
+    r.skip(session_id)?;
+    r.prefixed()?; // cipher suites
+    let compression = r.u8()? as usize;
+    r.skip(compression)?;
+
+    // A large ClientHello may not have arrived in full; read the
+    // extensions that have.
+    let len = r.u16()? as usize;
+    let mut extensions = Reader(&r.0[..len.min(r.0.len())]);
+    while let (Some(kind), Some(len)) = (extensions.u16(), extensions.u16()) {
+        let body = extensions.take(len as usize)?;
+        if kind != 0x0000 {
+            continue;
+        }
+        let mut names = Reader(body);
+        names.skip(2)?; // list length
+        while let Some(name_type) = names.u8() {
+            let name = names.prefixed()?;
+            if name_type == 0 {
+                return Some(name);
+            }
+        }
+    }
+    None
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn skip(&mut self, n: usize) -> Option<()> {
+        self.take(n).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    /// A field preceded by its length as a u16.
+    fn prefixed(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()?;
+        self.take(len as usize)
+    }
+}
+
+fn printable(bytes: &[u8]) -> String {
+    crate::printable(&String::from_utf8_lossy(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HELLO: &[u8] = include_bytes!("../tests/data/client_hello_sni.bin");
+    const HELLO_NO_SNI: &[u8] = include_bytes!("../tests/data/client_hello_no_sni.bin");
+
+    #[test]
+    fn tls_with_sni() {
+        assert_eq!(classify(HELLO).as_deref(), Some("tls sni=example.org"));
+    }
+
+    #[test]
+    fn tls_without_sni() {
+        assert_eq!(classify(HELLO_NO_SNI).as_deref(), Some("tls"));
+    }
+
+    #[test]
+    fn truncated_tls() {
+        // The first read can end partway through the extensions; anything
+        // parsed by the end of server_name is enough.
+        let name_end = HELLO.windows(11).position(|w| w == b"example.org").unwrap() + 11;
+        assert_eq!(
+            classify(&HELLO[..name_end]).as_deref(),
+            Some("tls sni=example.org")
+        );
+        assert_eq!(classify(&HELLO[..name_end - 1]).as_deref(), Some("tls"));
+        assert_eq!(classify(&HELLO[..10]).as_deref(), Some("tls"));
+    }
+
+    #[test]
+    fn http_host_any_case_and_spacing() {
+        let request = b"GET / HTTP/1.1\r\nAccept: */*\r\nhOsT: \t example.test  \r\n\r\n";
+        assert_eq!(
+            classify(request).as_deref(),
+            Some("http method=GET host=example.test")
+        );
+    }
+
+    #[test]
+    fn http_host_only_from_headers() {
+        let request = b"POST / HTTP/1.1\r\n\r\nHost: body.test\r\n";
+        assert_eq!(classify(request).as_deref(), Some("http method=POST"));
+        assert_eq!(classify(b"GETX / HTTP/1.1\r\n"), None);
+    }
+
+    #[test]
+    fn ssh_banner() {
+        assert_eq!(
+            classify(b"SSH-2.0-OpenSSH_9.6\r\n\0\0").as_deref(),
+            Some("ssh banner=SSH-2.0-OpenSSH_9.6")
+        );
+    }
+
+    #[test]
+    fn unknown_protocol() {
+        assert_eq!(classify(b"\x00\x01\x02"), None);
+        assert_eq!(classify(b""), None);
+    }
+}
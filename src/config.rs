@@ -0,0 +1,571 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use log::Level;
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Username/password attempts allowed on one connection before it is
+    /// dropped. Above 1 this breaks RFC 1929, which says the server MUST
+    /// close the connection after a failure status.
+    pub max_auth_attempts: u32,
+    /// Handshakes from one IP that break the protocol (bad version or
+    /// request, no acceptable method, failed auth) within
+    /// `handshake_failure_window` before its new connections are dropped
+    /// for `handshake_failure_cooldown`. Requests refused by policy or by
+    /// the network do not count. `None` disables the throttle.
+    pub handshake_failure_limit: Option<u32>,
+    pub handshake_failure_window: Duration,
+    pub handshake_failure_cooldown: Duration,
+    /// Keep this file updated with the IPs in handshake-failure cooldown,
+    /// for a firewall or fail2ban to enforce.
+    pub ban_list_file: Option<PathBuf>,
+    /// CONNECTs from one IP to the same destination whose lookup or dial
+    /// failed within `destination_failure_window` before further requests for it are
+    /// refused for `destination_failure_backoff`. `None` disables this.
+    pub destination_failure_limit: Option<u32>,
+    pub destination_failure_window: Duration,
+    pub destination_failure_backoff: Duration,
+    /// Answer CONNECTs to a destination that was unreachable, refused or
+    /// failed to resolve within this long with the same reply, without
+    /// dialing again. `None` disables this.
+    pub negative_cache_ttl: Option<Duration>,
+    /// Destinations remembered at once by the negative cache.
+    pub negative_cache_size: usize,
+    /// Instead of closing connections from IPs in cooldown, hold them open
+    /// without answering for up to this long.
+    pub tarpit: Option<Duration>,
+    /// Connections held in the tarpit at once. Further ones from IPs in
+    /// cooldown are closed straight away.
+    pub max_tarpitted: usize,
+    /// Level for relays torn down by a reset, broken pipe or timeout.
+    /// Other relay errors are always logged as warnings.
+    pub relay_error_log_level: Level,
+    /// Close tunnels where neither side sends anything within this long
+    /// after the CONNECT reply.
+    pub first_byte_timeout: Option<Duration>,
+    /// Close connections that finish negotiation but do not send their
+    /// whole request, header and address, within this long.
+    pub command_timeout: Option<Duration>,
+    /// Log running byte counts for each tunnel this often, not only when
+    /// it closes.
+    pub stats_interval: Option<Duration>,
+    /// Log a summary line for the whole server this often: active and
+    /// total connections, bytes relayed by closed tunnels, and uptime.
+    pub server_stats_interval: Option<Duration>,
+    /// Track each tunnel's highest throughput over windows this long and
+    /// log it, with the totals, when the tunnel closes.
+    pub peak_rate_window: Option<Duration>,
+    /// Abort the connection if a handshake reply cannot be written in time.
+    pub reply_timeout: Option<Duration>,
+    /// Send the outbound socket's local address in CONNECT replies instead
+    /// of zeroes. Off by default so replies don't reveal the egress address.
+    pub report_bound_addr: bool,
+    /// Longest CONNECT domain accepted, in bytes. The wire format already
+    /// caps it at 255; 253 is the longest name DNS can carry.
+    pub max_domain_len: usize,
+    /// Refuse CONNECT domains that are not valid RFC 1123 hostnames, before
+    /// they reach the resolver.
+    pub strict_hostnames: bool,
+    /// How long a single DNS query for a CONNECT domain may take.
+    pub dns_timeout: Duration,
+    /// Extra attempts after a timed-out DNS query before replying
+    /// `HostUnreached`. Lookups that fail outright are not retried.
+    pub dns_retries: u32,
+    /// DNS queries in flight at once. Requests over the limit wait up to
+    /// `dns_timeout` for a slot, then get `HostUnreached`.
+    pub max_dns_queries: Option<usize>,
+    /// Different domains whose resolutions may time out in a row before the
+    /// resolver is treated as down and further ones fail straight away with
+    /// `HostUnreached`. One query is still tried every
+    /// `dns_breaker_cooldown` to notice it coming back.
+    pub dns_breaker_threshold: Option<u32>,
+    pub dns_breaker_cooldown: Duration,
+    /// Answer SOCKS4 requests with a SOCKS4 "rejected" reply. When off
+    /// they are closed without a reply.
+    pub reply_socks4: bool,
+    /// Accept clients that offer no authentication. When off, they get
+    /// `0xff` and are logged as `method_disabled` rather than
+    /// `no_acceptable_method`.
+    pub noauth_enabled: bool,
+    /// Serve CONNECT requests. BIND and UDP ASSOCIATE are not implemented
+    /// and always get `CommandUnsupported`.
+    pub connect_enabled: bool,
+    /// Answer command codes outside the spec with `CommandUnsupported`.
+    /// When off they are closed without a reply.
+    pub reply_unknown_command: bool,
+    /// Refuse CONNECT requests for destination port 0, before any rewrite
+    /// or lookup.
+    pub reject_port_zero: bool,
+    /// Refuse CONNECT requests that would connect back to our own listener.
+    pub reject_self_connect: bool,
+    /// Refuse CONNECT requests whose destination resolves to the client's
+    /// own IP.
+    pub reject_client_destination: bool,
+    /// Interface index used as the scope of IPv6 link-local destinations,
+    /// which SOCKS has no way to carry. `None` refuses them with
+    /// `NetworkUnreached`.
+    pub link_local_scope: Option<u32>,
+    /// Connections handled at once. `None` means no limit.
+    pub max_connections: Option<usize>,
+    /// Warn, at most once a minute, while more connections than this are
+    /// active. Set it below `max_connections` to hear about load before
+    /// clients are turned away.
+    pub connection_warn_threshold: Option<usize>,
+    /// How long to stop accepting after `accept` fails, for example when
+    /// out of file descriptors or socket memory.
+    pub fd_exhaustion_pause: Duration,
+    /// What to do with new clients while `max_connections` are active.
+    pub overflow: Overflow,
+    /// Connections still in the handshake at once. New connections over
+    /// the limit are closed; a slot frees up when the relay starts.
+    pub max_handshakes: Option<usize>,
+    /// Connections that have not finished authenticating at once. New
+    /// connections over the limit are closed; a slot frees up as soon as
+    /// authentication succeeds.
+    pub max_unauthenticated: Option<usize>,
+    /// Bytes a client may send before the relay starts. A full-size
+    /// greeting, one full-size password attempt and a full-size request
+    /// come to about 1 KiB.
+    pub max_handshake_bytes: usize,
+    /// Log one info line per established tunnel with the client, user,
+    /// auth method, destination as requested and as resolved, and how long
+    /// each handshake stage took.
+    pub access_log: bool,
+    /// Peek at the first bytes of each tunnel and log the protocol: TLS
+    /// with its SNI, HTTP with its method and Host, or an SSH banner.
+    pub classify_protocols: bool,
+    /// Log a line the first time each username authenticates after startup.
+    pub log_new_users: bool,
+    /// Log one JSON record per connection when it closes.
+    pub connection_record: bool,
+    /// SO_SNDBUF for the listener, inherited by client sockets, and for
+    /// outbound sockets unless a custom dialer is set. `None` keeps the OS
+    /// default.
+    pub send_buffer_size: Option<usize>,
+    /// SO_RCVBUF for the listener, inherited by client sockets, and for
+    /// outbound sockets unless a custom dialer is set. `None` keeps the OS
+    /// default.
+    pub recv_buffer_size: Option<usize>,
+}
+
+/// Behaviour of the accept loop once `max_connections` is reached.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Overflow {
+    /// Stop accepting until a connection finishes. New clients wait in the
+    /// kernel's listen queue, which is invisible to them but adds latency,
+    /// and once the queue is full their SYNs are dropped or refused.
+    Backlog,
+    /// Keep accepting and close excess connections straight away. Clients
+    /// see an immediate failure they can back off from, at the cost of an
+    /// accept and close per rejected connection.
+    Shed,
+}
+
+impl FromStr for Overflow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "backlog" => Ok(Overflow::Backlog),
+            "shed" => Ok(Overflow::Shed),
+            _ => Err(format!("unknown overflow strategy '{}'", s)),
+        }
+    }
+}
+
+/// Longest duration any option accepts: a century, far below what would
+/// overflow an `Instant`.
+const MAX_DURATION: Duration = Duration::from_secs(100 * 365 * 24 * 60 * 60);
+
+/// One problem found in a configuration, naming the option at fault.
+/// Other options mentioned in `message` are in backticks.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConfigError {
+    pub field: String,
+    pub message: String,
+}
+
+impl ConfigError {
+    pub fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        ConfigError {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+
+    /// Renames the option at fault and any mentioned in the message, for
+    /// example to the command-line flags that set them.
+    pub fn rename(&self, name: impl Fn(&str) -> String) -> ConfigError {
+        let message = self
+            .message
+            .split('`')
+            .enumerate()
+            .map(|(i, part)| {
+                if i % 2 == 1 {
+                    name(part)
+                } else {
+                    part.to_string()
+                }
+            })
+            .collect();
+        ConfigError {
+            field: name(&self.field),
+            message,
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Checks values that parse but cannot work, returning every problem
+    /// rather than stopping at the first.
+    pub fn validate(&self) -> Vec<ConfigError> {
+        let mut errors = Vec::new();
+        let mut check = |ok: bool, field: &str, message: String| {
+            if !ok {
+                errors.push(ConfigError::new(field, message));
+            }
+        };
+
+        check(
+            self.max_auth_attempts > 0,
+            "max_auth_attempts",
+            String::from("must be at least 1, got 0"),
+        );
+        check(
+            self.handshake_failure_limit != Some(0),
+            "handshake_failure_limit",
+            String::from("must be at least 1, got 0"),
+        );
+        check(
+            self.destination_failure_limit != Some(0),
+            "destination_failure_limit",
+            String::from("must be at least 1, got 0"),
+        );
+        check(
+            self.dns_breaker_threshold != Some(0),
+            "dns_breaker_threshold",
+            String::from("must be at least 1, got 0"),
+        );
+        check(
+            self.dns_breaker_threshold.is_none() || !self.dns_breaker_cooldown.is_zero(),
+            "dns_breaker_cooldown",
+            String::from("must be greater than zero when `dns_breaker_threshold` is set"),
+        );
+        check(
+            self.handshake_failure_limit.is_none() || !self.handshake_failure_window.is_zero(),
+            "handshake_failure_window",
+            String::from("must be greater than zero when `handshake_failure_limit` is set"),
+        );
+        check(
+            (1..=255).contains(&self.max_domain_len),
+            "max_domain_len",
+            format!("must be between 1 and 255, got {}", self.max_domain_len),
+        );
+        check(
+            !self.fd_exhaustion_pause.is_zero(),
+            "fd_exhaustion_pause",
+            String::from("must be greater than zero"),
+        );
+        check(
+            !self.dns_timeout.is_zero(),
+            "dns_timeout",
+            String::from("must be greater than zero"),
+        );
+        for (field, timeout) in [
+            ("tarpit", self.tarpit),
+            ("first_byte_timeout", self.first_byte_timeout),
+            ("command_timeout", self.command_timeout),
+            ("reply_timeout", self.reply_timeout),
+            ("negative_cache_ttl", self.negative_cache_ttl),
+            ("peak_rate_window", self.peak_rate_window),
+            ("stats_interval", self.stats_interval),
+            ("server_stats_interval", self.server_stats_interval),
+        ] {
+            check(
+                timeout != Some(Duration::ZERO),
+                field,
+                String::from("must be greater than zero"),
+            );
+        }
+        // Deadlines are computed as `now + duration`, which panics if it
+        // overflows an Instant.
+        for (field, duration) in [
+            (
+                "handshake_failure_window",
+                Some(self.handshake_failure_window),
+            ),
+            (
+                "handshake_failure_cooldown",
+                Some(self.handshake_failure_cooldown),
+            ),
+            (
+                "destination_failure_window",
+                Some(self.destination_failure_window),
+            ),
+            (
+                "destination_failure_backoff",
+                Some(self.destination_failure_backoff),
+            ),
+            ("negative_cache_ttl", self.negative_cache_ttl),
+            ("tarpit", self.tarpit),
+            ("first_byte_timeout", self.first_byte_timeout),
+            ("command_timeout", self.command_timeout),
+            ("stats_interval", self.stats_interval),
+            ("server_stats_interval", self.server_stats_interval),
+            ("peak_rate_window", self.peak_rate_window),
+            ("reply_timeout", self.reply_timeout),
+            ("dns_timeout", Some(self.dns_timeout)),
+            ("dns_breaker_cooldown", Some(self.dns_breaker_cooldown)),
+            ("fd_exhaustion_pause", Some(self.fd_exhaustion_pause)),
+        ] {
+            check(
+                duration.is_none_or(|d| d <= MAX_DURATION),
+                field,
+                format!("must be at most {} seconds", MAX_DURATION.as_secs()),
+            );
+        }
+        for (field, limit) in [
+            ("max_connections", self.max_connections),
+            ("connection_warn_threshold", self.connection_warn_threshold),
+            ("max_handshakes", self.max_handshakes),
+            ("max_unauthenticated", self.max_unauthenticated),
+            ("max_dns_queries", self.max_dns_queries),
+            ("negative_cache_size", Some(self.negative_cache_size)),
+            ("max_tarpitted", Some(self.max_tarpitted)),
+            ("max_handshake_bytes", Some(self.max_handshake_bytes)),
+            ("send_buffer_size", self.send_buffer_size),
+            ("recv_buffer_size", self.recv_buffer_size),
+        ] {
+            check(
+                limit != Some(0),
+                field,
+                String::from("must be at least 1, got 0"),
+            );
+        }
+
+        errors
+    }
+}
+
+impl Config {
+    /// Options that are set but have no effect in the current mode.
+    pub fn ignored(&self) -> Vec<ConfigError> {
+        let default = Config::default();
+        let mut ignored = Vec::new();
+        let mut check = |unused: bool, field: &str, reason: &str| {
+            if unused {
+                ignored.push(ConfigError::new(field, reason));
+            }
+        };
+
+        let no_throttle = self.handshake_failure_limit.is_none();
+        let reason = "has no effect without `handshake_failure_limit`";
+        check(no_throttle && self.tarpit.is_some(), "tarpit", reason);
+        check(
+            self.tarpit.is_none() && self.max_tarpitted != default.max_tarpitted,
+            "max_tarpitted",
+            "has no effect without `tarpit`",
+        );
+        check(
+            no_throttle && self.ban_list_file.is_some(),
+            "ban_list_file",
+            reason,
+        );
+        check(
+            no_throttle && self.handshake_failure_window != default.handshake_failure_window,
+            "handshake_failure_window",
+            reason,
+        );
+        check(
+            no_throttle && self.handshake_failure_cooldown != default.handshake_failure_cooldown,
+            "handshake_failure_cooldown",
+            reason,
+        );
+
+        let reason = "has no effect without `destination_failure_limit`";
+        let no_limit = self.destination_failure_limit.is_none();
+        check(
+            no_limit && self.destination_failure_window != default.destination_failure_window,
+            "destination_failure_window",
+            reason,
+        );
+        check(
+            no_limit && self.destination_failure_backoff != default.destination_failure_backoff,
+            "destination_failure_backoff",
+            reason,
+        );
+
+        check(
+            self.dns_breaker_threshold.is_none()
+                && self.dns_breaker_cooldown != default.dns_breaker_cooldown,
+            "dns_breaker_cooldown",
+            "has no effect without `dns_breaker_threshold`",
+        );
+        check(
+            self.negative_cache_ttl.is_none()
+                && self.negative_cache_size != default.negative_cache_size,
+            "negative_cache_size",
+            "has no effect without `negative_cache_ttl`",
+        );
+
+        check(
+            self.max_connections.is_none() && self.overflow != default.overflow,
+            "overflow",
+            "has no effect without `max_connections`",
+        );
+        check(
+            matches!(
+                (self.connection_warn_threshold, self.max_connections),
+                (Some(threshold), Some(max)) if threshold >= max
+            ),
+            "connection_warn_threshold",
+            "is never exceeded because it is not below `max_connections`",
+        );
+
+        if !self.connect_enabled {
+            let reason = "has no effect while CONNECT is disabled";
+            check(
+                self.first_byte_timeout.is_some(),
+                "first_byte_timeout",
+                reason,
+            );
+            check(self.peak_rate_window.is_some(), "peak_rate_window", reason);
+            check(
+                self.report_bound_addr != default.report_bound_addr,
+                "report_bound_addr",
+                reason,
+            );
+            check(
+                self.max_domain_len != default.max_domain_len,
+                "max_domain_len",
+                reason,
+            );
+            check(
+                self.strict_hostnames != default.strict_hostnames,
+                "strict_hostnames",
+                reason,
+            );
+            check(
+                self.dns_timeout != default.dns_timeout,
+                "dns_timeout",
+                reason,
+            );
+            check(
+                self.dns_retries != default.dns_retries,
+                "dns_retries",
+                reason,
+            );
+            check(self.max_dns_queries.is_some(), "max_dns_queries", reason);
+            check(
+                self.reject_port_zero != default.reject_port_zero,
+                "reject_port_zero",
+                reason,
+            );
+            check(
+                self.reject_self_connect != default.reject_self_connect,
+                "reject_self_connect",
+                reason,
+            );
+            check(
+                self.reject_client_destination != default.reject_client_destination,
+                "reject_client_destination",
+                reason,
+            );
+            check(self.link_local_scope.is_some(), "link_local_scope", reason);
+        }
+
+        ignored
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            max_auth_attempts: 1,
+            handshake_failure_limit: None,
+            handshake_failure_window: Duration::from_secs(60),
+            handshake_failure_cooldown: Duration::from_secs(300),
+            ban_list_file: None,
+            destination_failure_limit: None,
+            destination_failure_window: Duration::from_secs(10),
+            destination_failure_backoff: Duration::from_secs(30),
+            negative_cache_ttl: None,
+            negative_cache_size: 1024,
+            tarpit: None,
+            max_tarpitted: 256,
+            relay_error_log_level: Level::Debug,
+            first_byte_timeout: None,
+            command_timeout: None,
+            stats_interval: None,
+            server_stats_interval: None,
+            peak_rate_window: None,
+            reply_timeout: None,
+            report_bound_addr: false,
+            max_domain_len: 255,
+            strict_hostnames: false,
+            dns_timeout: Duration::from_secs(10),
+            dns_retries: 1,
+            max_dns_queries: None,
+            dns_breaker_threshold: None,
+            dns_breaker_cooldown: Duration::from_secs(10),
+            reply_socks4: true,
+            noauth_enabled: true,
+            connect_enabled: true,
+            reply_unknown_command: true,
+            reject_port_zero: true,
+            reject_self_connect: true,
+            reject_client_destination: false,
+            link_local_scope: None,
+            max_connections: None,
+            connection_warn_threshold: None,
+            fd_exhaustion_pause: Duration::from_secs(1),
+            overflow: Overflow::Backlog,
+            max_handshakes: None,
+            max_unauthenticated: None,
+            max_handshake_bytes: 4096,
+            access_log: true,
+            classify_protocols: false,
+            log_new_users: true,
+            connection_record: false,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_durations_that_overflow() {
+        let config = Config {
+            handshake_failure_cooldown: Duration::from_secs(10_000_000_000_000_000_000),
+            ..Config::default()
+        };
+        let errors = config.validate();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].field, "handshake_failure_cooldown");
+    }
+
+    #[test]
+    fn rename_covers_mentioned_options() {
+        let error = ConfigError::new("tarpit", "has no effect without `handshake_failure_limit`");
+        let renamed = error.rename(|field| format!("--{}", field.replace('_', "-")));
+        assert_eq!(
+            renamed.to_string(),
+            "--tarpit: has no effect without --handshake-failure-limit"
+        );
+    }
+}
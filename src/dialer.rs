@@ -0,0 +1,113 @@
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+#[cfg(all(feature = "tcp-info", target_os = "linux"))]
+use std::os::fd::{AsRawFd, RawFd};
+use std::pin::Pin;
+
+use socket2::SockRef;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpSocket, TcpStream};
+
+/// What the server knows about a connection when it dials the destination.
+#[derive(Clone, Debug)]
+pub struct DialContext {
+    pub peer_addr: SocketAddr,
+    pub user: Option<String>,
+}
+
+/// Any transport the relay can read from and write to.
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for T {}
+
+pub type BoxStream = Box<dyn AsyncStream>;
+
+/// An outbound connection made by a `Dialer`.
+pub struct Dialed {
+    pub stream: BoxStream,
+    /// Local address of the outbound socket, reported to the client as
+    /// BND.ADDR. Transports without one leave it zeroed.
+    pub local_addr: Option<SocketAddr>,
+    /// The socket to read TCP_INFO from, for transports that are TCP.
+    #[cfg(all(feature = "tcp-info", target_os = "linux"))]
+    pub(crate) tcp_fd: Option<RawFd>,
+}
+
+impl Dialed {
+    /// A connection over any transport, with no local address.
+    pub fn new(stream: impl AsyncStream + 'static) -> Self {
+        Dialed {
+            stream: Box::new(stream),
+            local_addr: None,
+            #[cfg(all(feature = "tcp-info", target_os = "linux"))]
+            tcp_fd: None,
+        }
+    }
+
+    pub fn with_local_addr(mut self, addr: SocketAddr) -> Self {
+        self.local_addr = Some(addr);
+        self
+    }
+}
+
+impl From<TcpStream> for Dialed {
+    fn from(stream: TcpStream) -> Self {
+        let local_addr = stream.local_addr().ok();
+        #[cfg(all(feature = "tcp-info", target_os = "linux"))]
+        let tcp_fd = Some(stream.as_raw_fd());
+        Dialed {
+            stream: Box::new(stream),
+            local_addr,
+            #[cfg(all(feature = "tcp-info", target_os = "linux"))]
+            tcp_fd,
+        }
+    }
+}
+
+pub type DialFuture<'a> = Pin<Box<dyn Future<Output = io::Result<Dialed>> + Send + 'a>>;
+
+pub trait Dialer: Send + Sync {
+    fn dial<'a>(&'a self, addr: SocketAddr, ctx: &'a DialContext) -> DialFuture<'a>;
+}
+
+/// Connects straight to the destination, ignoring the context.
+#[derive(Clone, Debug, Default)]
+pub struct TcpDialer {
+    /// SO_SNDBUF for outbound sockets. `None` keeps the OS default.
+    pub send_buffer_size: Option<usize>,
+    /// SO_RCVBUF for outbound sockets. `None` keeps the OS default.
+    pub recv_buffer_size: Option<usize>,
+}
+
+impl Dialer for TcpDialer {
+    fn dial<'a>(&'a self, addr: SocketAddr, _ctx: &'a DialContext) -> DialFuture<'a> {
+        Box::pin(async move {
+            let socket = match addr {
+                SocketAddr::V4(_) => TcpSocket::new_v4()?,
+                SocketAddr::V6(_) => TcpSocket::new_v6()?,
+            };
+            set_buffer_sizes(
+                SockRef::from(&socket),
+                self.send_buffer_size,
+                self.recv_buffer_size,
+            )?;
+            let stream = socket.connect(addr).await?;
+            Ok(Dialed::from(stream))
+        })
+    }
+}
+
+pub(crate) fn set_buffer_sizes(
+    socket: SockRef,
+    send: Option<usize>,
+    recv: Option<usize>,
+) -> io::Result<()> {
+    if let Some(size) = send {
+        socket.set_send_buffer_size(size)?;
+    }
+    if let Some(size) = recv {
+        socket.set_recv_buffer_size(size)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,37 @@
+use std::future::Future;
+use std::pin::Pin;
+
+use crate::{AsyncStream, DialContext};
+
+/// One side of an established tunnel. Borrows from the connection, so
+/// wrappers cannot outlive the relay.
+pub type RelayStream<'a> = Box<dyn AsyncStream + 'a>;
+
+pub type EstablishedFuture<'a> =
+    Pin<Box<dyn Future<Output = (RelayStream<'a>, RelayStream<'a>)> + Send + 'a>>;
+
+/// Called once the CONNECT has succeeded and before any data is relayed.
+/// Returns the client and upstream streams to relay between, which may be
+/// the ones passed in or wrappers around them.
+pub trait EstablishedHook: Send + Sync {
+    fn on_established<'a>(
+        &'a self,
+        client: RelayStream<'a>,
+        upstream: RelayStream<'a>,
+        ctx: &'a DialContext,
+    ) -> EstablishedFuture<'a>;
+}
+
+/// Relays the streams unchanged.
+pub struct PassThrough;
+
+impl EstablishedHook for PassThrough {
+    fn on_established<'a>(
+        &'a self,
+        client: RelayStream<'a>,
+        upstream: RelayStream<'a>,
+        _ctx: &'a DialContext,
+    ) -> EstablishedFuture<'a> {
+        Box::pin(async move { (client, upstream) })
+    }
+}
@@ -1,9 +1,50 @@
+use std::collections::HashSet;
+use std::fmt::{self, Write as _};
+use std::net::{IpAddr, SocketAddr};
+#[cfg(all(feature = "tcp-info", target_os = "linux"))]
+use std::os::fd::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+use std::time::{Duration, Instant, SystemTime};
+
 use anyhow::Result;
-use log::{error, info};
-use tokio::io::{copy_bidirectional, AsyncReadExt, AsyncWriteExt, BufStream};
-use tokio::net::{TcpListener, TcpStream};
+use log::{debug, error, info, log, warn, Level};
+use socket2::SockRef;
+use tokio::io::{
+    copy_bidirectional, AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader,
+    BufStream,
+};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+mod banlist;
+mod classify;
+mod config;
+mod counted;
+mod dialer;
+mod established;
+mod negative_cache;
+mod record;
+mod resolver;
+mod rewrite;
+#[cfg(all(feature = "tcp-info", target_os = "linux"))]
+mod tcp_info;
+mod throttle;
+
+pub use config::{Config, ConfigError, Overflow};
+pub use dialer::{AsyncStream, BoxStream, DialContext, DialFuture, Dialed, Dialer, TcpDialer};
+pub use established::{EstablishedFuture, EstablishedHook, PassThrough, RelayStream};
+pub use rewrite::{Destination, DestinationRewriter, IdentityRewriter, RewriteFuture};
+#[cfg(all(feature = "tcp-info", target_os = "linux"))]
+pub use tcp_info::TcpInfo;
+
+use counted::Counted;
+use negative_cache::NegativeCache;
+use resolver::Resolver;
+use throttle::FailureTracker;
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug)]
 enum Method {
     Noauth = 0x00,
     //GSSAPI = 0x01,
@@ -11,15 +52,77 @@ enum Method {
     Error = 0xff,
 }
 
-enum AuthMethod {
-    Passwd = 0x01,
-}
+/// Version bytes checked in requests and echoed in replies.
+const SOCKS5_VERSION: u8 = 0x05;
+const SOCKS4_VERSION: u8 = 0x04;
+/// RFC 1929 username/password subnegotiation.
+const PASSWD_AUTH_VERSION: u8 = 0x01;
 
 struct Connection {
     stream: BufStream<TcpStream>,
-    version: u8,
+    id: u64,
+    peer_addr: SocketAddr,
+    shared: Arc<Shared>,
+    method: Option<Method>,
+    user: Option<String>,
+    started: Instant,
+    started_at: SystemTime,
+    stage_start: Instant,
+    stage_times: [Duration; 3],
+    handshake_bytes: usize,
+    /// Address family of the CONNECT request: "ipv4", "ipv6" or "domain".
+    requested_family: Option<&'static str>,
+    destination: Option<SocketAddr>,
+    reply: Option<CommandRep>,
+    /// The CONNECT failed in the destination's lookup or dial, rather than
+    /// on policy or one of our own limits.
+    destination_failed: bool,
+    bytes: (u64, u64),
+    /// Highest up and down rates seen over a `peak_rate_window`, in bytes
+    /// per second.
+    peak_rate: Option<(u64, u64)>,
+    close_reason: Option<CloseReason>,
+    handshake_permit: Option<OwnedSemaphorePermit>,
+    unauthenticated_permit: Option<OwnedSemaphorePermit>,
+    /// How long accepting was held back waiting for a `max_connections`
+    /// slot before this connection was taken from the backlog.
+    queue_wait: Option<Duration>,
+}
+
+struct Shared {
+    listen_addr: SocketAddr,
+    config: Config,
+    dialer: Arc<dyn Dialer>,
+    rewriter: Arc<dyn DestinationRewriter>,
+    established: Arc<dyn EstablishedHook>,
+    resolver: Resolver,
+    handshake_failures: FailureTracker<IpAddr>,
+    destination_failures: FailureTracker<(IpAddr, Destination)>,
+    failed_destinations: NegativeCache<Destination>,
+    connection_limit: Option<Arc<Semaphore>>,
+    handshake_limit: Option<Arc<Semaphore>>,
+    unauthenticated_limit: Option<Arc<Semaphore>>,
+    tarpit_limit: Arc<Semaphore>,
+    started: Instant,
+    next_id: AtomicU64,
+    active_connections: AtomicUsize,
+    /// Bytes relayed by tunnels that have closed.
+    bytes_up: AtomicU64,
+    bytes_down: AtomicU64,
+    last_load_warning: Mutex<Option<Instant>>,
+    ban_list_lock: Mutex<()>,
+    /// Usernames that have authenticated since startup.
+    seen_users: Mutex<HashSet<String>>,
 }
 
+/// SOCKS4 reply code for "request rejected or failed".
+const SOCKS4_REJECTED: u8 = 0x5b;
+
+const LOAD_WARNING_INTERVAL: Duration = Duration::from_secs(60);
+/// How often the load is checked between accepts. Shorter than the warning
+/// interval so a repeat warning isn't held back by a whole extra tick.
+const LOAD_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
 enum Command {
     Connect = 0x01,
     //BIND = 0x02,
@@ -27,12 +130,13 @@ enum Command {
     Unsupported = 0x04,
 }
 
+#[derive(Clone, Copy, Debug)]
 enum CommandRep {
     Succeeded = 0x00,
-    //ServerError = 0x01,
+    ServerError = 0x01,
     RuleSetNotAllowed = 0x02,
-    //NetworkUnreached = 0x03,
-    //HostUnreached = 0x04,
+    NetworkUnreached = 0x03,
+    HostUnreached = 0x04,
     ConnectionRefused = 0x05,
     //TTLExpired = 0x06,
     CommandUnsupported = 0x07,
@@ -46,199 +150,1109 @@ enum AddrType {
     Unsupported = 0x05,
 }
 
+impl AddrType {
+    fn family(&self) -> Option<&'static str> {
+        match self {
+            AddrType::V4 => Some("ipv4"),
+            AddrType::Domain => Some("domain"),
+            AddrType::V6 => Some("ipv6"),
+            AddrType::Unsupported => None,
+        }
+    }
+}
+
 enum Stage {
     Method,
     Auth,
     Command,
 }
 
-pub async fn run(addr: &str) -> std::io::Result<()> {
+#[derive(Clone, Copy, Debug)]
+enum CloseReason {
+    Eof,
+    ConnectionReset,
+    BrokenPipe,
+    TimedOut,
+    NoDataTimeout,
+    Error,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum RejectReason {
+    Throttled,
+    BadVersion,
+    Socks4,
+    AuthBeforeMethod,
+    NoAcceptableMethod,
+    MethodDisabled,
+    AuthFailed,
+    MalformedRequest,
+    CommandUnsupported,
+    UnknownCommand,
+    AddrTypeUnsupported,
+    DomainTooLong,
+    InvalidHostname,
+    PortZero,
+    SelfConnect,
+    ClientDestination,
+    LinkLocal,
+    ConnectionLimit,
+    HandshakeLimit,
+    UnauthenticatedLimit,
+    HandshakeTooLarge,
+    DestinationBackoff,
+    CachedFailure,
+    CommandTimeout,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub git_commit: &'static str,
+    /// Seconds since the Unix epoch, from `SOURCE_DATE_EPOCH` when set.
+    pub build_timestamp: u64,
+}
+
+pub fn build_info() -> BuildInfo {
+    BuildInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_commit: env!("SOCKS5_SERVER_GIT_COMMIT"),
+        build_timestamp: env!("SOCKS5_SERVER_BUILD_TIMESTAMP").parse().unwrap_or(0),
+    }
+}
+
+impl fmt::Display for BuildInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}, built at {})",
+            self.version, self.git_commit, self.build_timestamp
+        )
+    }
+}
+
+/// The points where an embedding application can change how connections
+/// are handled.
+pub struct Extensions {
+    /// `None` dials plain TCP with the config's socket buffer sizes.
+    pub dialer: Option<Arc<dyn Dialer>>,
+    pub rewriter: Arc<dyn DestinationRewriter>,
+    pub established: Arc<dyn EstablishedHook>,
+}
+
+impl Default for Extensions {
+    fn default() -> Self {
+        Extensions {
+            dialer: None,
+            rewriter: Arc::new(IdentityRewriter),
+            established: Arc::new(PassThrough),
+        }
+    }
+}
+
+pub async fn run(addr: &str, config: Config) -> std::io::Result<()> {
     env_logger::init();
+    serve(addr, config, Extensions::default()).await
+}
+
+pub async fn serve(addr: &str, config: Config, extensions: Extensions) -> std::io::Result<()> {
+    let listener = listen(addr, &config)
+        .await
+        .map_err(|e| std::io::Error::new(e.kind(), format!("failed to bind {}: {}", addr, e)))?;
+    serve_listener(listener, config, extensions).await
+}
 
-    let listener = TcpListener::bind(addr).await?;
-    info!("Server bind to {}", addr);
+/// Binds the listener `serve` uses. The socket buffer sizes from `config`
+/// are set before `listen`, so accepted connections inherit them and the
+/// receive window is scaled for them during the TCP handshake.
+pub async fn listen(addr: &str, config: &Config) -> std::io::Result<TcpListener> {
+    let addr = tokio::net::lookup_host(addr)
+        .await?
+        .next()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "no addresses found"))?;
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.set_reuseaddr(true)?;
+    dialer::set_buffer_sizes(
+        SockRef::from(&socket),
+        config.send_buffer_size,
+        config.recv_buffer_size,
+    )?;
+    socket.bind(addr)?;
+    socket.listen(1024)
+}
+
+/// Like `serve`, on a listener the caller has already bound. The socket
+/// buffer sizes are applied to it too, but only `listen` can set them
+/// before the first connections arrive.
+pub async fn serve_listener(
+    listener: TcpListener,
+    config: Config,
+    extensions: Extensions,
+) -> std::io::Result<()> {
+    dialer::set_buffer_sizes(
+        SockRef::from(&listener),
+        config.send_buffer_size,
+        config.recv_buffer_size,
+    )?;
+    let dialer = extensions.dialer.unwrap_or_else(|| {
+        Arc::new(TcpDialer {
+            send_buffer_size: config.send_buffer_size,
+            recv_buffer_size: config.recv_buffer_size,
+        })
+    });
+    let established: Arc<dyn EstablishedHook> = if config.classify_protocols {
+        Arc::new(classify::ProtocolClassifier {
+            inner: extensions.established,
+        })
+    } else {
+        extensions.established
+    };
+    let shared = Arc::new(Shared {
+        listen_addr: listener.local_addr()?,
+        handshake_failures: FailureTracker::new(
+            config.handshake_failure_limit,
+            config.handshake_failure_window,
+            config.handshake_failure_cooldown,
+        ),
+        destination_failures: FailureTracker::new(
+            config.destination_failure_limit,
+            config.destination_failure_window,
+            config.destination_failure_backoff,
+        ),
+        failed_destinations: NegativeCache::new(
+            config.negative_cache_ttl,
+            config.negative_cache_size,
+        ),
+        resolver: Resolver::new(
+            config.dns_timeout,
+            config.dns_retries,
+            config.max_dns_queries,
+            config
+                .dns_breaker_threshold
+                .map(|threshold| (threshold, config.dns_breaker_cooldown)),
+        ),
+        connection_limit: config.max_connections.map(|n| Arc::new(Semaphore::new(n))),
+        handshake_limit: config.max_handshakes.map(|n| Arc::new(Semaphore::new(n))),
+        unauthenticated_limit: config
+            .max_unauthenticated
+            .map(|n| Arc::new(Semaphore::new(n))),
+        tarpit_limit: Arc::new(Semaphore::new(config.max_tarpitted)),
+        config,
+        dialer,
+        rewriter: extensions.rewriter,
+        established,
+        started: Instant::now(),
+        next_id: AtomicU64::new(1),
+        active_connections: AtomicUsize::new(0),
+        bytes_up: AtomicU64::new(0),
+        bytes_down: AtomicU64::new(0),
+        last_load_warning: Mutex::new(None),
+        ban_list_lock: Mutex::new(()),
+        seen_users: Mutex::new(HashSet::new()),
+    });
+    info!("socks5-server {}", build_info());
+    info!("Server bind to {}", shared.listen_addr);
+    if let Some(interval) = shared.config.server_stats_interval {
+        let shared = shared.clone();
+        tokio::spawn(async move {
+            let mut ticker =
+                tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+            loop {
+                ticker.tick().await;
+                shared.log_stats();
+            }
+        });
+    }
+    if shared.config.connection_warn_threshold.is_some() {
+        // Accepts check the load too; this keeps warning while it stays
+        // high even if no new clients arrive.
+        let shared = shared.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(LOAD_CHECK_INTERVAL);
+            loop {
+                ticker.tick().await;
+                shared.check_load(shared.active_connections.load(Ordering::Relaxed));
+            }
+        });
+    }
 
     loop {
-        let (stream, _) = listener.accept().await?;
-        info!("{}: New connection", stream.peer_addr()?);
+        let queue_start = Instant::now();
+        let mut permit = match (&shared.connection_limit, shared.config.overflow) {
+            (Some(limit), Overflow::Backlog) => Some(
+                limit
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .expect("semaphore closed"),
+            ),
+            _ => None,
+        };
+        // Only a connection already pending once a slot came free has been
+        // queued; one that arrives later waited no time at all.
+        let pending = std::future::poll_fn(|cx| match listener.poll_accept(cx) {
+            Poll::Ready(accepted) => Poll::Ready(Some(accepted)),
+            Poll::Pending => Poll::Ready(None),
+        })
+        .await;
+        let queue_wait = permit.as_ref().map(|_| match pending {
+            Some(_) => queue_start.elapsed(),
+            None => Duration::ZERO,
+        });
+        let accepted = match pending {
+            Some(accepted) => accepted,
+            None => listener.accept().await,
+        };
 
+        let (stream, peer_addr) = match accepted {
+            Ok(accepted) => accepted,
+            // The client gave up before we took it; the next one is fine.
+            Err(e) if is_transient_accept_error(&e) => {
+                debug!("Accept failed: {}", e);
+                continue;
+            }
+            // Anything else is likely to fail again straight away, so
+            // back off rather than spin.
+            Err(e) => {
+                let pause = shared.config.fd_exhaustion_pause;
+                if is_resource_exhaustion(&e) {
+                    error!("Out of resources, pausing accepts for {:?}: {}", pause, e);
+                } else {
+                    warn!("Accept failed, pausing accepts for {:?}: {}", pause, e);
+                }
+                tokio::time::sleep(pause).await;
+                continue;
+            }
+        };
+        if shared.handshake_failures.is_blocked(&peer_addr.ip()) {
+            log_rejection(peer_addr, RejectReason::Throttled);
+            if let Some(duration) = shared.config.tarpit {
+                match shared.tarpit_limit.clone().try_acquire_owned() {
+                    Ok(permit) => {
+                        tokio::spawn(async move {
+                            tarpit(stream, duration).await;
+                            drop(permit);
+                        });
+                    }
+                    Err(_) => debug!("{}: Tarpit full, closing", peer_addr),
+                }
+            }
+            continue;
+        }
+        if let (None, Some(limit)) = (&permit, &shared.connection_limit) {
+            match limit.clone().try_acquire_owned() {
+                Ok(p) => permit = Some(p),
+                Err(_) => {
+                    log_rejection(peer_addr, RejectReason::ConnectionLimit);
+                    continue;
+                }
+            }
+        }
+        let handshake_permit = match &shared.handshake_limit {
+            Some(limit) => match limit.clone().try_acquire_owned() {
+                Ok(p) => Some(p),
+                Err(_) => {
+                    log_rejection(peer_addr, RejectReason::HandshakeLimit);
+                    continue;
+                }
+            },
+            None => None,
+        };
+        let unauthenticated_permit = match &shared.unauthenticated_limit {
+            Some(limit) => match limit.clone().try_acquire_owned() {
+                Ok(p) => Some(p),
+                Err(_) => {
+                    log_rejection(peer_addr, RejectReason::UnauthenticatedLimit);
+                    continue;
+                }
+            },
+            None => None,
+        };
+        info!("{}: New connection", peer_addr);
+        let active = shared.active_connections.fetch_add(1, Ordering::Relaxed) + 1;
+        shared.check_load(active);
+
+        let shared = shared.clone();
         tokio::spawn(async move {
-            let mut connection = Connection::new(stream);
-            let _ = connection.handle().await;
+            let mut connection = Connection::new(stream, peer_addr, shared);
+            connection.handshake_permit = handshake_permit;
+            connection.unauthenticated_permit = unauthenticated_permit;
+            connection.queue_wait = queue_wait;
+            let result = connection.handle().await;
+            if connection.shared.config.connection_record {
+                connection.log_record(&result);
+            }
+            drop(permit);
+            connection
+                .shared
+                .active_connections
+                .fetch_sub(1, Ordering::Relaxed);
         });
     }
 }
 
+impl Shared {
+    fn log_stats(&self) {
+        info!(
+            "Stats: {} active connections, {} total, {} bytes up, {} bytes down, up {}s",
+            self.active_connections.load(Ordering::Relaxed),
+            self.next_id.load(Ordering::Relaxed) - 1,
+            self.bytes_up.load(Ordering::Relaxed),
+            self.bytes_down.load(Ordering::Relaxed),
+            self.started.elapsed().as_secs()
+        );
+    }
+
+    /// Warns, at most once per `LOAD_WARNING_INTERVAL`, while more than
+    /// `connection_warn_threshold` connections are active.
+    fn check_load(&self, active: usize) {
+        let threshold = match self.config.connection_warn_threshold {
+            Some(threshold) if active > threshold => threshold,
+            _ => return,
+        };
+
+        let now = Instant::now();
+        let mut last = self.last_load_warning.lock().unwrap();
+        if last.is_some_and(|last| now - last < LOAD_WARNING_INTERVAL) {
+            return;
+        }
+        *last = Some(now);
+        match self.config.max_connections {
+            Some(max) => warn!(
+                "{} active connections, above the warning threshold of {} (limit {})",
+                active, threshold, max
+            ),
+            None => warn!(
+                "{} active connections, above the warning threshold of {}",
+                active, threshold
+            ),
+        }
+    }
+}
+
+/// Logs TCP_INFO for the client socket and, if the dialer gave one, the
+/// upstream socket. Both must still be open.
+#[cfg(all(feature = "tcp-info", target_os = "linux"))]
+fn log_tcp_info(peer_addr: SocketAddr, (client, upstream): (RawFd, Option<RawFd>)) {
+    let show =
+        |info: Option<TcpInfo>| info.map_or_else(|| String::from("unavailable"), |i| i.to_string());
+    debug!(
+        "{}: TCP stats client {}; upstream {}",
+        peer_addr,
+        show(TcpInfo::sample(&client)),
+        show(upstream.and_then(|fd| TcpInfo::sample(&fd)))
+    );
+}
+
+/// Relays like `copy_bidirectional`, logging the running byte counts
+/// every `progress` so long transfers show up before they finish, and
+/// recording in `peak` the highest up and down rates, in bytes per
+/// second, seen over any `peak_window`.
+async fn copy_sampled<A, B>(
+    peer_addr: SocketAddr,
+    progress: Option<Duration>,
+    peak_window: Option<Duration>,
+    peak: &mut (u64, u64),
+    client: &mut A,
+    upstream: &mut B,
+) -> std::io::Result<(u64, u64)>
+where
+    A: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + ?Sized,
+    B: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + ?Sized,
+{
+    let (mut upstream, counters) = Counted::new(upstream);
+    let copy = copy_bidirectional(client, &mut upstream);
+    tokio::pin!(copy);
+
+    let ticker =
+        |period: Duration| tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    let mut progress_ticker = progress.map(ticker);
+    let mut peak_ticker = peak_window.map(ticker);
+    let mut last = (0, 0);
+    loop {
+        let totals = || {
+            (
+                counters.written.load(Ordering::Relaxed),
+                counters.read.load(Ordering::Relaxed),
+            )
+        };
+        tokio::select! {
+            result = &mut copy => return result,
+            _ = tick(&mut progress_ticker) => {
+                let (up, down) = totals();
+                info!(
+                    "{}: Relay progress: {} bytes up, {} bytes down",
+                    peer_addr, up, down
+                );
+            }
+            _ = tick(&mut peak_ticker) => {
+                let secs = peak_window.unwrap().as_secs_f64();
+                let (up, down) = totals();
+                let rate = |bytes: u64| (bytes as f64 / secs) as u64;
+                peak.0 = peak.0.max(rate(up - last.0));
+                peak.1 = peak.1.max(rate(down - last.1));
+                last = (up, down);
+            }
+        }
+    }
+}
+
+/// Waits for the next tick, or forever when there is no ticker.
+async fn tick(ticker: &mut Option<tokio::time::Interval>) {
+    match ticker {
+        Some(ticker) => {
+            ticker.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// RFC 1123 hostname syntax: dot-separated labels of 1 to 63 letters,
+/// digits and hyphens, not starting or ending with a hyphen, 253 bytes at
+/// most with an optional trailing dot. IP literals are accepted too.
+fn is_hostname(host: &str) -> bool {
+    if host.parse::<IpAddr>().is_ok() {
+        return true;
+    }
+    let host = host.strip_suffix('.').unwrap_or(host);
+    host.len() <= 253
+        && host.split('.').all(|label| {
+            (1..=63).contains(&label.len())
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        })
+}
+
+/// EMFILE or ENFILE (24 and 23 on Unix): the process or the system has
+/// run out of file descriptors. ENOBUFS or ENOMEM: the kernel is out of
+/// memory for sockets. Retrying straight away would just fail again.
+fn is_resource_exhaustion(e: &std::io::Error) -> bool {
+    const ENOBUFS: i32 = if cfg!(target_os = "linux") { 105 } else { 55 };
+    e.kind() == std::io::ErrorKind::OutOfMemory
+        || matches!(e.raw_os_error(), Some(23 | 24 | ENOBUFS))
+}
+
+/// Accept errors about one connection rather than the listener, such as
+/// a client that reset before we accepted it.
+fn is_transient_accept_error(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+
+    matches!(
+        e.kind(),
+        ErrorKind::ConnectionAborted | ErrorKind::ConnectionReset | ErrorKind::Interrupted
+    )
+}
+
+/// Runs one read of the request, failing with `CommandTimeout` once the
+/// whole request has taken past `deadline`.
+async fn before_deadline<T>(
+    deadline: Option<tokio::time::Instant>,
+    read: impl std::future::Future<Output = Result<T>>,
+) -> Result<T> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, read)
+            .await
+            .map_err(|_| RejectReason::CommandTimeout)?,
+        None => read.await,
+    }
+}
+
+/// Holds a throttled client's connection open, discarding whatever it
+/// sends, until it gives up or `duration` has passed.
+async fn tarpit(mut stream: TcpStream, duration: Duration) {
+    let mut buf = [0u8; 64];
+    let drain = async {
+        while let Ok(n) = stream.read(&mut buf).await {
+            if n == 0 {
+                break;
+            }
+        }
+    };
+    let _ = tokio::time::timeout(duration, drain).await;
+}
+
 impl Connection {
-    pub fn new(stream: TcpStream) -> Self {
+    fn new(stream: TcpStream, peer_addr: SocketAddr, shared: Arc<Shared>) -> Self {
+        let now = Instant::now();
         Connection {
             stream: BufStream::with_capacity(513, 261, stream),
-            version: 5u8,
+            id: shared.next_id.fetch_add(1, Ordering::Relaxed),
+            peer_addr,
+            shared,
+            method: None,
+            user: None,
+            started: now,
+            started_at: SystemTime::now(),
+            stage_start: now,
+            stage_times: [Duration::ZERO; 3],
+            handshake_bytes: 0,
+            requested_family: None,
+            destination: None,
+            reply: None,
+            destination_failed: false,
+            bytes: (0, 0),
+            peak_rate: None,
+            close_reason: None,
+            handshake_permit: None,
+            unauthenticated_permit: None,
+            queue_wait: None,
         }
     }
 
     async fn handle(&mut self) -> Result<()> {
-        let peer_addr = self.stream.get_ref().peer_addr().map_err(|e| {
-            error!("{}", e);
-            e
-        })?;
+        let peer_addr = self.peer_addr;
+
+        let shared = self.shared.clone();
 
-        let err = |e: &_| {
-            error!("{}: {}", peer_addr, e);
+        let err = |e: &anyhow::Error| {
+            match e.downcast_ref::<RejectReason>() {
+                Some(&reason) => log_rejection(peer_addr, reason),
+                None => error!("{}: {}", peer_addr, e),
+            }
             info!("{}: {}", peer_addr, "Close connection");
         };
+        let fail = |e: &anyhow::Error| {
+            err(e);
+            let protocol_error = matches!(
+                e.downcast_ref::<RejectReason>(),
+                Some(reason) if reason.is_protocol_error()
+            );
+            if protocol_error {
+                record_handshake_failure(&shared, peer_addr);
+            }
+        };
 
-        let method = self.negotiate_method().await.map_err(|e| {
-            err(&e);
-            e
-        })?;
-        self.reply_method(method).await.map_err(|e| {
-            err(&e);
-            e
-        })?;
-
-        self.auth(method).await.map_err(|e| {
-            err(&e);
-            e
-        })?;
-
-        self.handle_command().await.map_err(|e| {
-            err(&e);
-            e
-        })?;
+        let method = self.negotiate_method().await.inspect_err(fail)?;
+        self.method = Some(method);
+        self.reply_method(method).await.inspect_err(fail)?;
+        self.finish_stage(Stage::Method);
+
+        self.auth(method).await.inspect_err(fail)?;
+        self.finish_stage(Stage::Auth);
+        self.unauthenticated_permit = None;
+
+        self.handle_command().await.inspect_err(fail)?;
 
         info!("{}: {}", peer_addr, "Close connection");
         Ok(())
     }
 
+    fn finish_stage(&mut self, stage: Stage) {
+        let now = Instant::now();
+        self.stage_times[stage as usize] = now - self.stage_start;
+        self.stage_start = now;
+    }
+
+    fn log_stage_times(&self) {
+        let [method, auth, command] = self.stage_times;
+        debug!(
+            "{}: Handshake took method {:?}, auth {:?}, command {:?}",
+            self.peer_addr, method, auth, command
+        );
+    }
+
     async fn handle_command(&mut self) -> Result<()> {
         use CommandRep::{CommandUnsupported, RuleSetNotAllowed};
         let mut buf = [0u8; 3];
+        let deadline = self
+            .shared
+            .config
+            .command_timeout
+            .map(|timeout| tokio::time::Instant::now() + timeout);
 
-        self.stream.read_exact(&mut buf).await?;
-        if buf[0] != self.version {
-            return self.reply_command(RuleSetNotAllowed).await;
+        before_deadline(deadline, self.read_exact(&mut buf)).await?;
+        if buf[0] != SOCKS5_VERSION {
+            self.reply_command(RuleSetNotAllowed).await?;
+            return Err(RejectReason::BadVersion.into());
+        }
+        if !(0x01..=0x03).contains(&buf[1]) {
+            info!("{}: Unknown command {:#04x}", self.peer_addr, buf[1]);
+            if self.shared.config.reply_unknown_command {
+                self.reply_command(CommandUnsupported).await?;
+            }
+            return Err(RejectReason::UnknownCommand.into());
         }
 
         match buf[1].into() {
-            Command::Connect => self.handle_connect_command().await,
-            _ => self.reply_command(CommandUnsupported).await,
+            Command::Connect if self.shared.config.connect_enabled => {
+                self.handle_connect_command(deadline).await
+            }
+            _ => {
+                self.reply_command(CommandUnsupported).await?;
+                Err(RejectReason::CommandUnsupported.into())
+            }
         }
     }
 
-    async fn handle_connect_command(&mut self) -> Result<()> {
-        let addr = self.read_addr().await?;
+    async fn handle_connect_command(
+        &mut self,
+        deadline: Option<tokio::time::Instant>,
+    ) -> Result<()> {
+        let requested = before_deadline(deadline, self.read_addr()).await?;
+        if requested.port() == 0 && self.shared.config.reject_port_zero {
+            self.reply_command(CommandRep::RuleSetNotAllowed).await?;
+            return Err(RejectReason::PortZero.into());
+        }
+        let key = (self.peer_addr.ip(), requested.clone());
+        if self.shared.destination_failures.is_blocked(&key) {
+            self.reply_command(CommandRep::RuleSetNotAllowed).await?;
+            return Err(RejectReason::DestinationBackoff.into());
+        }
+        if let Some(rep) = self.shared.failed_destinations.get(&requested) {
+            debug!(
+                "{}: {} failed recently, replying {:?}",
+                self.peer_addr,
+                printable(&requested.to_string()),
+                rep
+            );
+            self.reply_command(rep).await?;
+            return Err(RejectReason::CachedFailure.into());
+        }
 
-        match TcpStream::connect(addr).await {
-            Err(_) => self.reply_command(CommandRep::ConnectionRefused).await,
-            Ok(mut connection) => {
-                self.reply_command(CommandRep::Succeeded).await?;
-                copy_bidirectional(&mut self.stream, &mut connection).await?;
+        let result = self.connect(requested.clone()).await;
+        if !self.destination_failed {
+            return result;
+        }
+        if let Some(
+            rep @ (CommandRep::NetworkUnreached
+            | CommandRep::HostUnreached
+            | CommandRep::ConnectionRefused),
+        ) = self.reply
+        {
+            self.shared.failed_destinations.insert(requested, rep);
+        }
+        if self.shared.destination_failures.record(key.clone()) {
+            warn!(
+                "{}: Repeated failures connecting to {}, backing off",
+                self.peer_addr,
+                printable(&key.1.to_string())
+            );
+        }
+        result
+    }
+
+    async fn connect(&mut self, requested: Destination) -> Result<()> {
+        let ctx = DialContext {
+            peer_addr: self.peer_addr,
+            user: self.user.clone(),
+        };
+        let dest = self.shared.rewriter.rewrite(&requested, &ctx).await;
+        if dest != requested {
+            debug!(
+                "{}: Rewrote {} to {}",
+                self.peer_addr,
+                printable(&requested.to_string()),
+                printable(&dest.to_string())
+            );
+        }
+        let mut addr = self.resolve(dest).await?;
+        if let SocketAddr::V6(v6) = &mut addr {
+            if v6.ip().is_unicast_link_local() && v6.scope_id() == 0 {
+                match self.shared.config.link_local_scope {
+                    Some(scope) => v6.set_scope_id(scope),
+                    None => {
+                        self.reply_command(CommandRep::NetworkUnreached).await?;
+                        return Err(RejectReason::LinkLocal.into());
+                    }
+                }
+            }
+        }
+        self.destination = Some(addr);
+        if self.shared.config.reject_self_connect && self.is_own_listener(addr) {
+            self.reply_command(CommandRep::RuleSetNotAllowed).await?;
+            return Err(RejectReason::SelfConnect.into());
+        }
+        if addr.ip().to_canonical() == self.peer_addr.ip().to_canonical() {
+            if self.shared.config.reject_client_destination {
+                self.reply_command(CommandRep::RuleSetNotAllowed).await?;
+                return Err(RejectReason::ClientDestination.into());
+            }
+            debug!(
+                "{}: Destination {} is the client itself",
+                self.peer_addr, addr
+            );
+        }
+        self.finish_stage(Stage::Command);
+        self.log_stage_times();
+
+        match self.shared.dialer.dial(addr, &ctx).await {
+            Err(e) if is_resource_exhaustion(&e) => {
+                error!(
+                    "{}: Out of resources connecting to {}: {}",
+                    self.peer_addr, addr, e
+                );
+                self.reply_command(CommandRep::ServerError).await
+            }
+            Err(e) => {
+                let rep = CommandRep::from(&e);
+                debug!(
+                    "{}: Connect to {} failed: {} ({:?})",
+                    self.peer_addr, addr, e, rep
+                );
+                self.destination_failed = true;
+                self.reply_command(rep).await
+            }
+            Ok(connection) => {
+                debug!("{}: Connected to {}", self.peer_addr, addr);
+                let bound = connection.local_addr;
+                let reported = bound.filter(|_| self.shared.config.report_bound_addr);
+                self.reply_command_bound(CommandRep::Succeeded, reported)
+                    .await?;
+                if self.shared.config.access_log {
+                    let line = self.access_line(&requested, addr, bound);
+                    info!("{}: Access {}", self.peer_addr, line);
+                }
+                self.relay(connection, &ctx).await;
                 Ok(())
             }
         }
     }
 
-    async fn read_addr(&mut self) -> Result<std::net::SocketAddr> {
-        use std::net::SocketAddr;
+    /// The `key=value` fields logged for an established tunnel. Values the
+    /// client chose are escaped, so they cannot end a field or a line.
+    fn access_line(
+        &self,
+        requested: &Destination,
+        resolved: SocketAddr,
+        bound: Option<SocketAddr>,
+    ) -> String {
+        let [method_time, auth_time, command_time] = self.stage_times;
+        let mut line = format!(
+            "client={} client_port={} user={} method={}",
+            self.peer_addr.ip(),
+            self.peer_addr.port(),
+            self.user
+                .as_deref()
+                .map_or_else(|| String::from("-"), printable),
+            self.method.map_or("-", |m| m.label())
+        );
+        let _ = write!(
+            line,
+            " command=connect requested={} resolved={} bound={} result=succeeded",
+            printable(&requested.to_string()),
+            resolved,
+            bound.map_or_else(|| String::from("-"), |b| b.to_string())
+        );
+        let _ = write!(
+            line,
+            " method_ms={} auth_ms={} command_ms={}",
+            method_time.as_millis(),
+            auth_time.as_millis(),
+            command_time.as_millis()
+        );
+        if let Some(wait) = self.queue_wait {
+            let _ = write!(line, " queue_ms={}", wait.as_millis());
+        }
+        line
+    }
+
+    /// Whether `addr` would loop back into this server's listener.
+    fn is_own_listener(&self, addr: SocketAddr) -> bool {
+        let listen = self.shared.listen_addr;
+        if addr.port() != listen.port() {
+            return false;
+        }
+
+        let ip = addr.ip();
+        let local_ip = self.stream.get_ref().local_addr().map(|a| a.ip()).ok();
+        ip == listen.ip()
+            || Some(ip) == local_ip
+            || ip.is_unspecified()
+            || (listen.ip().is_unspecified() && ip.is_loopback())
+    }
+
+    async fn relay(&mut self, upstream: Dialed, ctx: &DialContext) {
+        self.handshake_permit = None;
+        #[cfg(all(feature = "tcp-info", target_os = "linux"))]
+        let fds = (self.stream.get_ref().as_raw_fd(), upstream.tcp_fd);
+        let mut upstream = BufReader::new(upstream.stream);
+        let (reason, detail) = if !self.wait_for_data(&mut upstream).await {
+            #[cfg(all(feature = "tcp-info", target_os = "linux"))]
+            log_tcp_info(self.peer_addr, fds);
+            (
+                CloseReason::NoDataTimeout,
+                String::from("no data before first-byte timeout"),
+            )
+        } else {
+            let (mut client, mut upstream) = self
+                .shared
+                .established
+                .on_established(Box::new(&mut self.stream), Box::new(upstream), ctx)
+                .await;
+            let config = &self.shared.config;
+            let result = match (config.stats_interval, config.peak_rate_window) {
+                (None, None) => copy_bidirectional(&mut client, &mut upstream).await,
+                (progress, peak_window) => {
+                    let mut peak = (0, 0);
+                    let result = copy_sampled(
+                        self.peer_addr,
+                        progress,
+                        peak_window,
+                        &mut peak,
+                        &mut client,
+                        &mut upstream,
+                    )
+                    .await;
+                    self.peak_rate = peak_window.map(|_| peak);
+                    result
+                }
+            };
+            #[cfg(all(feature = "tcp-info", target_os = "linux"))]
+            log_tcp_info(self.peer_addr, fds);
+            match result {
+                Ok((up, down)) => {
+                    self.bytes = (up, down);
+                    self.shared.bytes_up.fetch_add(up, Ordering::Relaxed);
+                    self.shared.bytes_down.fetch_add(down, Ordering::Relaxed);
+                    let mut detail = format!("{} bytes up, {} bytes down", up, down);
+                    if let Some((peak_up, peak_down)) = self.peak_rate {
+                        let _ = write!(detail, ", peak {} B/s up, {} B/s down", peak_up, peak_down);
+                    }
+                    (CloseReason::Eof, detail)
+                }
+                Err(e) => (CloseReason::from(&e), e.to_string()),
+            }
+        };
+
+        self.close_reason = Some(reason);
+        let level = match reason {
+            CloseReason::Eof => Level::Debug,
+            CloseReason::Error => Level::Warn,
+            _ => self.shared.config.relay_error_log_level,
+        };
+
+        log!(
+            level,
+            "{}: Relay closed ({:?}): {}",
+            self.peer_addr,
+            reason,
+            detail
+        );
+    }
+
+    /// Waits until either side has data, giving up after
+    /// `first_byte_timeout`. Nothing is consumed from either stream.
+    async fn wait_for_data<S: AsyncBufRead + Unpin>(&mut self, upstream: &mut S) -> bool {
+        let timeout = match self.shared.config.first_byte_timeout {
+            Some(timeout) => timeout,
+            None => return true,
+        };
+
+        let data = async {
+            tokio::select! {
+                _ = self.stream.fill_buf() => {}
+                _ = upstream.fill_buf() => {}
+            }
+        };
+        tokio::time::timeout(timeout, data).await.is_ok()
+    }
 
-        let addr_type: AddrType = self.stream.read_u8().await?.into();
+    async fn read_addr(&mut self) -> Result<Destination> {
+        let addr_type: AddrType = self.read_u8().await?.into();
+        self.requested_family = addr_type.family();
 
         match addr_type {
             AddrType::V4 => {
                 let mut addr = [0u8; 4];
-                self.stream.read_exact(&mut addr).await?;
-                let port = self.stream.read_u16().await?;
+                self.read_exact(&mut addr).await?;
+                let port = self.read_u16().await?;
 
-                Ok(SocketAddr::from((addr, port)))
+                Ok(Destination::Addr(SocketAddr::from((addr, port))))
             }
             AddrType::V6 => {
                 let mut addr = [0u8; 16];
-                self.stream.read_exact(&mut addr).await?;
-                let port = self.stream.read_u16().await?;
+                self.read_exact(&mut addr).await?;
+                let port = self.read_u16().await?;
 
-                Ok(SocketAddr::from((addr, port)))
+                Ok(Destination::Addr(SocketAddr::from((addr, port))))
             }
             AddrType::Domain => {
                 let domain = self.read_variable(Stage::Command).await?;
-                let port = self.stream.read_u16().await?;
-
-                use std::net::ToSocketAddrs;
-                (std::str::from_utf8(&domain)?, port)
-                    .to_socket_addrs()?
-                    .next()
-                    .ok_or(anyhow::anyhow!(""))
+                let port = self.read_u16().await?;
+                if domain.len() > self.shared.config.max_domain_len {
+                    self.reply_command(CommandRep::HostUnreached).await?;
+                    return Err(RejectReason::DomainTooLong.into());
+                }
+                match String::from_utf8(domain) {
+                    Ok(host) if self.shared.config.strict_hostnames && !is_hostname(&host) => {
+                        self.reply_command(CommandRep::HostUnreached).await?;
+                        Err(RejectReason::InvalidHostname.into())
+                    }
+                    Ok(host) => Ok(Destination::Domain(host, port)),
+                    Err(_) => {
+                        self.reply_command(CommandRep::HostUnreached).await?;
+                        Err(RejectReason::MalformedRequest.into())
+                    }
+                }
             }
             _ => {
                 self.reply_command(CommandRep::AddrTypeUnsupported).await?;
-                anyhow::bail!("Unsupported address type")
+                Err(RejectReason::AddrTypeUnsupported.into())
+            }
+        }
+    }
+
+    async fn resolve(&mut self, dest: Destination) -> Result<SocketAddr> {
+        let (host, port) = match dest {
+            Destination::Addr(addr) => return Ok(addr),
+            Destination::Domain(host, port) => (host, port),
+        };
+
+        match self.shared.resolver.resolve(&host, port).await {
+            Ok(addr) => Ok(addr),
+            Err(e) => {
+                // Busy means we never asked: the query limit or the
+                // breaker turned the lookup away.
+                self.destination_failed = e.kind() != std::io::ErrorKind::ResourceBusy;
+                self.reply_command(CommandRep::HostUnreached).await?;
+                anyhow::bail!("Failed to resolve {}: {}", printable(&host), e)
             }
         }
     }
 
+    /// Every handshake read goes through here, so a client cannot make us
+    /// read more than `max_handshake_bytes` before the relay starts.
+    async fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
+        self.handshake_bytes += buf.len();
+        if self.handshake_bytes > self.shared.config.max_handshake_bytes {
+            return Err(RejectReason::HandshakeTooLarge.into());
+        }
+        self.stream.read_exact(buf).await?;
+        Ok(())
+    }
+
+    async fn read_u8(&mut self) -> Result<u8> {
+        let mut buf = [0u8; 1];
+        self.read_exact(&mut buf).await?;
+        Ok(buf[0])
+    }
+
+    async fn read_u16(&mut self) -> Result<u16> {
+        let mut buf = [0u8; 2];
+        self.read_exact(&mut buf).await?;
+        Ok(u16::from_be_bytes(buf))
+    }
+
     async fn read_variable(&mut self, stage: Stage) -> Result<Vec<u8>> {
-        let len = match self.stream.read_u8().await? {
+        let len = match self.read_u8().await? {
             0 => {
                 match stage {
                     Stage::Command => self.reply_command(CommandRep::RuleSetNotAllowed).await?,
                     Stage::Method => self.reply_method(Method::Error).await?,
-                    Stage::Auth => self.reply_auth(AuthMethod::Passwd, false).await?,
+                    Stage::Auth => self.reply_auth(false).await?,
                 }
-                anyhow::bail!("Error lens of variable field")
+                return Err(RejectReason::MalformedRequest.into());
             }
             n => n,
         } as usize;
 
         let mut buf = vec![0; len];
-        self.stream.read_exact(&mut buf).await?;
+        self.read_exact(&mut buf).await?;
 
         Ok(buf)
     }
 
     async fn reply_command(&mut self, rep: CommandRep) -> Result<()> {
-        let buf = vec![
-            self.version,
-            rep as u8,
-            0,
-            AddrType::V4 as u8,
-            0,
-            0,
-            0,
-            0,
-            0,
-            0,
-        ];
-        self.stream.write_all(&buf).await?;
-        self.stream.flush().await?;
+        self.reply_command_bound(rep, None).await
+    }
+
+    async fn reply_command_bound(
+        &mut self,
+        rep: CommandRep,
+        bound: Option<SocketAddr>,
+    ) -> Result<()> {
+        self.reply = Some(rep);
+        let mut buf = vec![SOCKS5_VERSION, rep as u8, 0];
+        match bound {
+            Some(SocketAddr::V4(addr)) => {
+                buf.push(AddrType::V4 as u8);
+                buf.extend_from_slice(&addr.ip().octets());
+                buf.extend_from_slice(&addr.port().to_be_bytes());
+            }
+            Some(SocketAddr::V6(addr)) => {
+                buf.push(AddrType::V6 as u8);
+                buf.extend_from_slice(&addr.ip().octets());
+                buf.extend_from_slice(&addr.port().to_be_bytes());
+            }
+            None => buf.extend_from_slice(&[AddrType::V4 as u8, 0, 0, 0, 0, 0, 0]),
+        }
+        self.write_reply(&buf).await
+    }
+
+    async fn write_reply(&mut self, buf: &[u8]) -> Result<()> {
+        let timeout = self.shared.config.reply_timeout;
+        let write = async {
+            self.stream.write_all(buf).await?;
+            self.stream.flush().await
+        };
+
+        match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, write)
+                .await
+                .map_err(|_| anyhow::anyhow!("Timed out writing reply"))??,
+            None => write.await?,
+        }
         Ok(())
     }
 
+    /// SOCKS4 clients are rejected as `socks4` so probes for the older,
+    /// unauthenticated protocol stand out from other bad versions.
+    ///
+    /// Clients may pipeline the username/password request right after the
+    /// greeting without waiting for our method reply; the bytes simply wait
+    /// in the read buffer. A client that skips the greeting and opens with
+    /// the auth request gets a `0xff` method reply and is closed with
+    /// `auth_before_method`.
     async fn negotiate_method(&mut self) -> Result<Method> {
-        if self.stream.read_u8().await? != self.version {
-            return Ok(Method::Error);
+        let version = self.read_u8().await?;
+        if version == SOCKS4_VERSION {
+            if self.shared.config.reply_socks4 {
+                self.write_reply(&[0x00, SOCKS4_REJECTED, 0, 0, 0, 0, 0, 0])
+                    .await?;
+            }
+            return Err(RejectReason::Socks4.into());
         }
-
-        let buf = self.read_variable(Stage::Method).await?;
-        if buf.contains(&(Method::Passwd as u8)) {
-            return Ok(Method::Passwd);
+        if version != SOCKS5_VERSION {
+            self.reply_method(Method::Error).await?;
+            if version == PASSWD_AUTH_VERSION {
+                return Err(RejectReason::AuthBeforeMethod.into());
+            }
+            return Err(RejectReason::BadVersion.into());
         }
-        if buf.contains(&(Method::Noauth as u8)) {
-            return Ok(Method::Noauth);
+
+        let offered = self.read_variable(Stage::Method).await?;
+        let noauth_offered = offered.contains(&(Method::Noauth as u8));
+        let method = if offered.contains(&(Method::Passwd as u8)) {
+            Method::Passwd
+        } else if noauth_offered && self.shared.config.noauth_enabled {
+            Method::Noauth
+        } else {
+            Method::Error
+        };
+
+        debug!(
+            "{}: Client offered methods {:02x?}, selected {:?}",
+            self.peer_addr, offered, method
+        );
+        if let Method::Error = method {
+            self.reply_method(method).await?;
+            if noauth_offered {
+                return Err(RejectReason::MethodDisabled.into());
+            }
+            return Err(RejectReason::NoAcceptableMethod.into());
         }
 
-        Ok(Method::Error)
+        Ok(method)
     }
 
     async fn reply_method(&mut self, method: Method) -> Result<()> {
-        self.stream
-            .write_u16(to_u16(self.version, method as u8))
-            .await?;
-        self.stream.flush().await?;
-
-        match method {
-            Method::Error => anyhow::bail!("Error method"),
-            _ => Ok(()),
-        }
+        self.write_reply(&[SOCKS5_VERSION, method as u8]).await
     }
 
     async fn auth(&mut self, method: Method) -> Result<()> {
@@ -249,29 +1263,175 @@ impl Connection {
         }
     }
 
-    async fn reply_auth(&mut self, method: AuthMethod, rep: bool) -> Result<()> {
-        self.stream
-            .write_u16(to_u16(method as u8, !rep as u8))
-            .await?;
-        self.stream.flush().await?;
-        Ok(())
+    async fn reply_auth(&mut self, rep: bool) -> Result<()> {
+        self.write_reply(&[PASSWD_AUTH_VERSION, !rep as u8]).await
     }
 
     async fn auth_passwd(&mut self) -> Result<()> {
-        self.stream.read_u8().await?;
-        let username = self.read_variable(Stage::Auth).await?;
-        let password = self.read_variable(Stage::Auth).await?;
+        let max_attempts = self.shared.config.max_auth_attempts.max(1);
+
+        for attempt in 1..=max_attempts {
+            if self.read_u8().await? != PASSWD_AUTH_VERSION {
+                self.reply_auth(false).await?;
+                return Err(RejectReason::BadVersion.into());
+            }
+            let username = self.read_variable(Stage::Auth).await?;
+            let password = self.read_variable(Stage::Auth).await?;
+
+            let simple = vec![49, 50, 51];
+            if username == simple && password == simple {
+                let user = String::from_utf8_lossy(&username).into_owned();
+                if self.shared.config.log_new_users
+                    && self.shared.seen_users.lock().unwrap().insert(user.clone())
+                {
+                    info!("{}: First connection for user {}", self.peer_addr, user);
+                }
+                self.user = Some(user);
+                return self.reply_auth(true).await;
+            }
+
+            warn!(
+                "{}: Auth failed (attempt {}/{})",
+                self.peer_addr, attempt, max_attempts
+            );
+            // Counted here rather than by the caller, so a client that
+            // reconnects after each wrong password is still counted.
+            record_handshake_failure(&self.shared, self.peer_addr);
+            self.reply_auth(false).await?;
+        }
+
+        Err(RejectReason::AuthFailed.into())
+    }
+}
+
+impl From<&std::io::Error> for CloseReason {
+    fn from(e: &std::io::Error) -> Self {
+        use std::io::ErrorKind;
+
+        match e.kind() {
+            ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted => {
+                CloseReason::ConnectionReset
+            }
+            ErrorKind::BrokenPipe => CloseReason::BrokenPipe,
+            ErrorKind::TimedOut => CloseReason::TimedOut,
+            _ => CloseReason::Error,
+        }
+    }
+}
+
+impl From<&std::io::Error> for CommandRep {
+    /// Maps a dial failure to its reply. An IPv6 destination on a host
+    /// without IPv6 fails with no route or no usable source address,
+    /// which is reported as `NetworkUnreached`.
+    fn from(e: &std::io::Error) -> Self {
+        use std::io::ErrorKind;
+
+        match e.kind() {
+            ErrorKind::NetworkUnreachable | ErrorKind::AddrNotAvailable => {
+                CommandRep::NetworkUnreached
+            }
+            ErrorKind::HostUnreachable => CommandRep::HostUnreached,
+            _ => CommandRep::ConnectionRefused,
+        }
+    }
+}
 
-        let simple = vec![49, 50, 51];
-        if username != simple || password != simple {
-            self.reply_auth(AuthMethod::Passwd, false).await?;
-            anyhow::bail!("Auth failed");
+impl Method {
+    fn label(self) -> &'static str {
+        match self {
+            Method::Noauth => "none",
+            Method::Passwd => "password",
+            Method::Error => "unacceptable",
         }
+    }
+}
+
+impl RejectReason {
+    /// Whether the client broke the protocol, as opposed to making a valid
+    /// request that policy or the network refused. Only these count
+    /// towards `handshake_failure_limit`. Failed passwords count too, but
+    /// `auth_passwd` records each attempt itself.
+    fn is_protocol_error(self) -> bool {
+        matches!(
+            self,
+            RejectReason::BadVersion
+                | RejectReason::AuthBeforeMethod
+                | RejectReason::NoAcceptableMethod
+                | RejectReason::MethodDisabled
+                | RejectReason::MalformedRequest
+                | RejectReason::HandshakeTooLarge
+                | RejectReason::UnknownCommand
+                | RejectReason::AddrTypeUnsupported
+        )
+    }
+
+    fn code(self) -> &'static str {
+        match self {
+            RejectReason::Throttled => "throttled",
+            RejectReason::BadVersion => "bad_version",
+            RejectReason::Socks4 => "socks4",
+            RejectReason::AuthBeforeMethod => "auth_before_method",
+            RejectReason::NoAcceptableMethod => "no_acceptable_method",
+            RejectReason::MethodDisabled => "method_disabled",
+            RejectReason::AuthFailed => "auth_failed",
+            RejectReason::MalformedRequest => "malformed_request",
+            RejectReason::CommandUnsupported => "command_unsupported",
+            RejectReason::UnknownCommand => "unknown_command",
+            RejectReason::AddrTypeUnsupported => "addr_type_unsupported",
+            RejectReason::DomainTooLong => "domain_too_long",
+            RejectReason::InvalidHostname => "invalid_hostname",
+            RejectReason::PortZero => "port_zero",
+            RejectReason::SelfConnect => "self_connect",
+            RejectReason::ClientDestination => "client_destination",
+            RejectReason::LinkLocal => "link_local",
+            RejectReason::ConnectionLimit => "connection_limit",
+            RejectReason::HandshakeLimit => "handshake_limit",
+            RejectReason::UnauthenticatedLimit => "unauthenticated_limit",
+            RejectReason::HandshakeTooLarge => "handshake_too_large",
+            RejectReason::DestinationBackoff => "destination_backoff",
+            RejectReason::CachedFailure => "cached_failure",
+            RejectReason::CommandTimeout => "command_timeout",
+        }
+    }
+}
 
-        self.reply_auth(AuthMethod::Passwd, true).await
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Rejected: {}", self.code())
     }
 }
 
+impl std::error::Error for RejectReason {}
+
+/// Escapes client-chosen text for a log line: control characters, quotes
+/// and backslashes as `escape_debug` does, and spaces as `\x20`.
+pub(crate) fn printable(text: &str) -> String {
+    text.escape_debug().to_string().replace(' ', "\\x20")
+}
+
+/// Counts a handshake failure against the client's IP, and starts its
+/// cooldown once it has too many.
+fn record_handshake_failure(shared: &Arc<Shared>, peer_addr: SocketAddr) {
+    if shared.handshake_failures.record(peer_addr.ip()) {
+        warn!("{}: Too many handshake failures, cooling down", peer_addr);
+        if shared.config.ban_list_file.is_some() {
+            let shared = shared.clone();
+            tokio::task::spawn_blocking(move || shared.export_bans());
+        }
+    }
+}
+
+/// The one place rejections are logged, so they can be matched on
+/// `reject=<code>` regardless of the stage they happened in.
+fn log_rejection(peer_addr: SocketAddr, reason: RejectReason) {
+    info!(
+        "{}: Rejected reject={} client={}",
+        peer_addr,
+        reason.code(),
+        peer_addr.ip()
+    );
+}
+
 impl From<u8> for Command {
     fn from(c: u8) -> Self {
         match c {
@@ -292,6 +1452,38 @@ impl From<u8> for AddrType {
     }
 }
 
-fn to_u16(a: u8, b: u8) -> u16 {
-    ((a as u16) << 8) + b as u16
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hostname_length_limits() {
+        let label = "a".repeat(63);
+        let name_253 = format!("{0}.{0}.{0}.{1}", label, "a".repeat(61));
+        assert_eq!(name_253.len(), 253);
+        assert!(is_hostname(&name_253));
+        assert!(is_hostname(&format!("{}.", name_253)));
+        assert!(!is_hostname(&format!("{}a", name_253)));
+        assert!(!is_hostname(&format!("{}.", "a".repeat(64))));
+    }
+
+    #[test]
+    fn hostname_labels() {
+        assert!(is_hostname("example.com."));
+        assert!(is_hostname("xn--bcher-kva.example"));
+        assert!(is_hostname("192.0.2.1"));
+        assert!(is_hostname("::1"));
+        assert!(!is_hostname("-example.com"));
+        assert!(!is_hostname("example-.com"));
+        assert!(!is_hostname("example..com"));
+        assert!(!is_hostname("example.com.."));
+        assert!(!is_hostname("exa mple.com"));
+        assert!(!is_hostname("under_score.com"));
+        assert!(!is_hostname(""));
+    }
+
+    #[test]
+    fn printable_escapes_field_breaks() {
+        assert_eq!(printable("a b\nc=d"), "a\\x20b\\nc=d");
+    }
 }
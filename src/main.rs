@@ -1,38 +1,341 @@
+use std::time::Duration;
+
+use socks5_server::{Config, ConfigError};
+
 #[tokio::main]
-async fn main() -> std::io::Result<()> {
-    match parse_args() {
-        Ok((addr, port)) => socks5_server::run(&format!("{}:{}", addr, port)).await,
-        _ => {
-            help();
-            Ok(())
+async fn main() {
+    let (addr, port, config) = match parse_args() {
+        Ok(args) => args,
+        Err(errors) => {
+            for e in &errors {
+                eprintln!("socks5_server: {}", e);
+            }
+            eprintln!("Try 'socks5_server --help' for usage.");
+            std::process::exit(2);
         }
+    };
+    for option in config.ignored() {
+        eprintln!("socks5_server: warning: {}", option.rename(flag_for));
+    }
+    if let Err(e) = socks5_server::run(&format!("{}:{}", addr, port), config).await {
+        eprintln!("socks5_server: {}", e);
+        std::process::exit(1);
     }
 }
 
-fn parse_args() -> Result<(String, String), lexopt::Error> {
-    use lexopt::prelude::*;
+/// The flag that sets a `Config` field, so every error names an option
+/// the user can actually type.
+fn flag_for(field: &str) -> String {
+    let flag = match field {
+        "reply_socks4" => "drop-socks4",
+        "noauth_enabled" => "disable-noauth",
+        "connect_enabled" => "disable-connect",
+        "reply_unknown_command" => "drop-unknown-commands",
+        "reject_port_zero" => "allow-port-zero",
+        "reject_self_connect" => "allow-self-connect",
+        "access_log" => "no-access-log",
+        "log_new_users" => "no-new-user-log",
+        field => return format!("--{}", field.replace('_', "-")),
+    };
+    format!("--{}", flag)
+}
 
+/// Parses every argument before giving up, so all mistakes are reported together.
+fn parse_args() -> Result<(String, u16, Config), Vec<ConfigError>> {
     let mut addr = String::from("127.0.0.1");
-    let mut port = String::from("1080");
+    let mut port = 1080;
+    let mut config = Config::default();
+    let mut errors = Vec::new();
 
     let mut parser = lexopt::Parser::from_env();
-    while let Some(arg) = parser.next()? {
-        match arg {
-            Short('b') => {
-                addr = parser.value()?.into_string()?;
+    loop {
+        let arg = match parser.next() {
+            Ok(Some(arg)) => arg,
+            Ok(None) => break,
+            Err(e) => {
+                errors.push(ConfigError::new("arguments", e.to_string()));
+                break;
             }
-            Short('p') => {
-                port = parser.value()?.into_string()?;
+        };
+        let field = match arg {
+            lexopt::Arg::Short(c) => format!("-{}", c),
+            lexopt::Arg::Long(name) => format!("--{}", name),
+            lexopt::Arg::Value(value) => {
+                errors.push(ConfigError::new(
+                    value.to_string_lossy(),
+                    "unexpected argument",
+                ));
+                continue;
             }
-            Long("help") => help(),
-            _ => help(),
+        };
+        if let Err(e) = apply_arg(&mut parser, &field, &mut addr, &mut port, &mut config) {
+            let message = match e {
+                lexopt::Error::UnexpectedOption(_) => String::from("unknown option"),
+                e => e.to_string(),
+            };
+            errors.push(ConfigError::new(field, message));
+        }
+    }
+
+    errors.extend(config.validate().iter().map(|e| e.rename(flag_for)));
+    if errors.is_empty() {
+        Ok((addr, port, config))
+    } else {
+        Err(errors)
+    }
+}
+
+fn apply_arg(
+    parser: &mut lexopt::Parser,
+    field: &str,
+    addr: &mut String,
+    port: &mut u16,
+    config: &mut Config,
+) -> Result<(), lexopt::Error> {
+    use lexopt::prelude::*;
+
+    match field {
+        "-b" => {
+            *addr = parser.value()?.into_string()?;
+        }
+        "-p" => {
+            *port = parser.value()?.parse()?;
+        }
+        "--max-auth-attempts" => {
+            config.max_auth_attempts = parser.value()?.parse()?;
+        }
+        "--handshake-failure-limit" => {
+            config.handshake_failure_limit = Some(parser.value()?.parse()?);
+        }
+        "--handshake-failure-window" => {
+            config.handshake_failure_window = Duration::from_secs(parser.value()?.parse()?);
+        }
+        "--handshake-failure-cooldown" => {
+            config.handshake_failure_cooldown = Duration::from_secs(parser.value()?.parse()?);
+        }
+        "--ban-list-file" => {
+            config.ban_list_file = Some(parser.value()?.into());
+        }
+        "--destination-failure-limit" => {
+            config.destination_failure_limit = Some(parser.value()?.parse()?);
+        }
+        "--destination-failure-window" => {
+            config.destination_failure_window = Duration::from_secs(parser.value()?.parse()?);
+        }
+        "--destination-failure-backoff" => {
+            config.destination_failure_backoff = Duration::from_secs(parser.value()?.parse()?);
+        }
+        "--negative-cache-ttl" => {
+            config.negative_cache_ttl = Some(Duration::from_secs(parser.value()?.parse()?));
+        }
+        "--negative-cache-size" => {
+            config.negative_cache_size = parser.value()?.parse()?;
+        }
+        "--tarpit" => {
+            config.tarpit = Some(Duration::from_secs(parser.value()?.parse()?));
+        }
+        "--max-tarpitted" => {
+            config.max_tarpitted = parser.value()?.parse()?;
+        }
+        "--relay-error-log-level" => {
+            config.relay_error_log_level = parser.value()?.parse()?;
+        }
+        "--first-byte-timeout" => {
+            config.first_byte_timeout = Some(Duration::from_secs(parser.value()?.parse()?));
+        }
+        "--command-timeout" => {
+            config.command_timeout = Some(Duration::from_secs(parser.value()?.parse()?));
+        }
+        "--stats-interval" => {
+            let secs = parser.value()?.parse()?;
+            config.stats_interval = Some(Duration::from_secs(secs)).filter(|_| secs > 0);
+        }
+        "--server-stats-interval" => {
+            let secs = parser.value()?.parse()?;
+            config.server_stats_interval = Some(Duration::from_secs(secs)).filter(|_| secs > 0);
+        }
+        "--peak-rate-window" => {
+            config.peak_rate_window = Some(Duration::from_secs(parser.value()?.parse()?));
         }
+        "--reply-timeout" => {
+            config.reply_timeout = Some(Duration::from_secs(parser.value()?.parse()?));
+        }
+        "--report-bound-addr" => {
+            config.report_bound_addr = true;
+        }
+        "--max-domain-len" => {
+            config.max_domain_len = parser.value()?.parse()?;
+        }
+        "--strict-hostnames" => {
+            config.strict_hostnames = true;
+        }
+        "--dns-timeout" => {
+            config.dns_timeout = Duration::from_secs(parser.value()?.parse()?);
+        }
+        "--dns-retries" => {
+            config.dns_retries = parser.value()?.parse()?;
+        }
+        "--drop-socks4" => {
+            config.reply_socks4 = false;
+        }
+        "--disable-noauth" => {
+            config.noauth_enabled = false;
+        }
+        "--max-dns-queries" => {
+            config.max_dns_queries = Some(parser.value()?.parse()?);
+        }
+        "--dns-breaker-threshold" => {
+            config.dns_breaker_threshold = Some(parser.value()?.parse()?);
+        }
+        "--dns-breaker-cooldown" => {
+            config.dns_breaker_cooldown = Duration::from_secs(parser.value()?.parse()?);
+        }
+        "--disable-connect" => {
+            config.connect_enabled = false;
+        }
+        "--drop-unknown-commands" => {
+            config.reply_unknown_command = false;
+        }
+        "--allow-port-zero" => {
+            config.reject_port_zero = false;
+        }
+        "--allow-self-connect" => {
+            config.reject_self_connect = false;
+        }
+        "--reject-client-destination" => {
+            config.reject_client_destination = true;
+        }
+        "--link-local-scope" => {
+            config.link_local_scope = Some(parser.value()?.parse()?);
+        }
+        "--max-connections" => {
+            config.max_connections = Some(parser.value()?.parse()?);
+        }
+        "--connection-warn-threshold" => {
+            config.connection_warn_threshold = Some(parser.value()?.parse()?);
+        }
+        "--fd-exhaustion-pause" => {
+            config.fd_exhaustion_pause = Duration::from_secs(parser.value()?.parse()?);
+        }
+        "--overflow" => {
+            config.overflow = parser.value()?.parse()?;
+        }
+        "--max-handshakes" => {
+            config.max_handshakes = Some(parser.value()?.parse()?);
+        }
+        "--max-unauthenticated" => {
+            config.max_unauthenticated = Some(parser.value()?.parse()?);
+        }
+        "--max-handshake-bytes" => {
+            config.max_handshake_bytes = parser.value()?.parse()?;
+        }
+        "--no-access-log" => {
+            config.access_log = false;
+        }
+        "--classify-protocols" => {
+            config.classify_protocols = true;
+        }
+        "--no-new-user-log" => {
+            config.log_new_users = false;
+        }
+        "--connection-record" => {
+            config.connection_record = true;
+        }
+        "--send-buffer-size" => {
+            config.send_buffer_size = Some(parser.value()?.parse()?);
+        }
+        "--recv-buffer-size" => {
+            config.recv_buffer_size = Some(parser.value()?.parse()?);
+        }
+        "-V" | "--version" => {
+            println!("socks5_server {}", socks5_server::build_info());
+            std::process::exit(0);
+        }
+        "--help" => help(),
+        _ => return Err(lexopt::Error::UnexpectedOption(field.to_owned())),
     }
 
-    Ok((addr, port))
+    Ok(())
 }
 
 fn help() {
-    println!("Usage: socks5_server [-b BIND_ADDR] [-p PORT]");
+    print!("{}", USAGE);
     std::process::exit(0);
 }
+
+const USAGE: &str = "\
+Usage: socks5_server [-b BIND_ADDR] [-p PORT] [OPTIONS]
+
+Options:
+  -V, --version                       print version and build information
+  --max-auth-attempts N               password attempts allowed per connection (default 1)
+  --handshake-failure-limit N         protocol errors per IP before a cooldown (default off)
+  --handshake-failure-window SECS     window the failures are counted in (default 60)
+  --handshake-failure-cooldown SECS   how long the IP is dropped for (default 300)
+  --tarpit SECS                       hold connections from IPs in cooldown open for up
+                                      to SECS instead of closing them (default off)
+  --max-tarpitted N                   connections held in the tarpit at once; more are
+                                      closed straight away (default 256)
+  --ban-list-file PATH                keep PATH updated with the IPs in cooldown, as
+                                      \"<ip> <banned_at> <until>\" lines (default off)
+  --destination-failure-limit N       failed CONNECTs from one IP to one destination
+                                      before refusing it for a while (default off)
+  --destination-failure-window SECS   window those failures are counted in (default 10)
+  --destination-failure-backoff SECS  how long the destination is refused for (default 30)
+  --negative-cache-ttl SECS           answer CONNECTs to a destination that just failed
+                                      with the same reply for SECS (default off)
+  --negative-cache-size N             destinations the negative cache holds (default 1024)
+  --relay-error-log-level LEVEL       level for resets and timeouts during relay (default debug)
+  --first-byte-timeout SECS           close tunnels that carry no data for this long (default off)
+  --command-timeout SECS              close clients that do not send their request this
+                                      long after negotiating (default off)
+  --stats-interval SECS               log running byte counts for each tunnel this often
+                                      (default 0, off)
+  --server-stats-interval SECS        log connection and byte totals for the whole server
+                                      this often (default 0, off)
+  --peak-rate-window SECS             log each tunnel's highest throughput over windows of
+                                      SECS when it closes (default off)
+  --reply-timeout SECS                abort if a handshake reply takes longer to send (default off)
+  --report-bound-addr                 send the outbound address as BND.ADDR in CONNECT
+                                      replies instead of zeroes
+  --max-domain-len N                  reject longer CONNECT domains (default 255)
+  --strict-hostnames                  refuse CONNECT domains that are not valid hostnames
+  --dns-timeout SECS                  time allowed per DNS query (default 10)
+  --dns-retries N                     retries of a timed-out DNS query (default 1)
+  --max-dns-queries N                 DNS queries in flight at once (default unlimited)
+  --dns-breaker-threshold N           domains timing out in a row before failing domain
+                                      CONNECTs fast while DNS is down (default off)
+  --dns-breaker-cooldown SECS         how often to retry DNS while failing fast (default 10)
+  --drop-socks4                       close SOCKS4 clients without a reply
+  --disable-noauth                    require username/password authentication
+  --disable-connect                   answer CONNECT with CommandUnsupported
+  --drop-unknown-commands             close clients sending a command code outside the
+                                      spec without a reply
+  --allow-port-zero                   pass CONNECT requests for port 0 on to the dialer
+  --allow-self-connect                allow CONNECT to the server's own listen address
+  --reject-client-destination         refuse CONNECT to the client's own IP
+  --link-local-scope INDEX            connect IPv6 link-local destinations through this
+                                      interface index (default refuse them)
+  --max-connections N                 connections handled at once (default unlimited)
+  --connection-warn-threshold N       warn once a minute while more connections are active
+                                      (default off)
+  --fd-exhaustion-pause SECS          stop accepting for this long after accept fails,
+                                      e.g. when out of file descriptors (default 1)
+  --overflow backlog|shed             leave excess clients in the listen queue, or close
+                                      them immediately (default backlog)
+  --max-handshakes N                  connections still in the handshake at once
+                                      (default unlimited)
+  --max-unauthenticated N             connections still negotiating or authenticating
+                                      at once (default unlimited)
+  --max-handshake-bytes N             bytes a client may send before the relay starts
+                                      (default 4096)
+  --no-access-log                     skip the info line logged for each established tunnel
+  --classify-protocols                log each tunnel's protocol (TLS SNI, HTTP Host, SSH
+                                      banner) from its first bytes
+  --no-new-user-log                   skip the info line logged the first time each user
+                                      authenticates
+  --connection-record                 log a JSON record per connection on the
+                                      socks5_server::record target
+  --send-buffer-size BYTES            SO_SNDBUF for client and outbound sockets (default OS)
+  --recv-buffer-size BYTES            SO_RCVBUF for client and outbound sockets (default OS)
+";
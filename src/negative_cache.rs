@@ -0,0 +1,57 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::CommandRep;
+
+/// Remembers destinations whose CONNECT just failed, and the reply it got,
+/// so repeated requests are answered without dialing again.
+pub(crate) struct NegativeCache<K> {
+    ttl: Option<Duration>,
+    capacity: usize,
+    entries: Mutex<HashMap<K, (CommandRep, Instant)>>,
+}
+
+impl<K: Hash + Eq + Clone> NegativeCache<K> {
+    pub fn new(ttl: Option<Duration>, capacity: usize) -> Self {
+        NegativeCache {
+            ttl,
+            capacity,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<CommandRep> {
+        self.ttl?;
+
+        let entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some(&(rep, expires)) if expires > Instant::now() => Some(rep),
+            _ => None,
+        }
+    }
+
+    pub fn insert(&self, key: K, rep: CommandRep) {
+        let ttl = match self.ttl {
+            Some(ttl) => ttl,
+            None => return,
+        };
+        let now = Instant::now();
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            entries.retain(|_, (_, expires)| *expires > now);
+        }
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            let soonest = entries
+                .iter()
+                .min_by_key(|(_, (_, expires))| *expires)
+                .map(|(key, _)| key.clone());
+            if let Some(soonest) = soonest {
+                entries.remove(&soonest);
+            }
+        }
+        entries.insert(key, (rep, now + ttl));
+    }
+}
@@ -0,0 +1,115 @@
+use std::fmt::Write;
+use std::time::{Duration, UNIX_EPOCH};
+
+use log::info;
+
+use crate::{Connection, RejectReason};
+
+impl Connection {
+    /// Emits everything known about the connection as one JSON object on
+    /// the `socks5_server::record` target. Filtering the crate's other
+    /// targets out of `RUST_LOG` leaves only these records.
+    pub(crate) fn log_record(&self, result: &anyhow::Result<()>) {
+        let [method, auth, command] = self.stage_times;
+        let start_ms = self
+            .started_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+
+        let mut fields = vec![
+            ("id", self.id.to_string()),
+            ("client", quote(&self.peer_addr.to_string())),
+            (
+                "client_family",
+                quote(if self.peer_addr.ip().to_canonical().is_ipv4() {
+                    "ipv4"
+                } else {
+                    "ipv6"
+                }),
+            ),
+            ("start_ms", start_ms.to_string()),
+            ("duration_ms", millis(self.started.elapsed())),
+            ("method_ms", millis(method)),
+            ("auth_ms", millis(auth)),
+            ("command_ms", millis(command)),
+            (
+                "queue_ms",
+                self.queue_wait.map_or_else(|| String::from("null"), millis),
+            ),
+            ("method", optional(self.method.map(|m| m.label()))),
+            ("user", optional(self.user.clone())),
+            ("requested_family", optional(self.requested_family)),
+            ("destination", optional(self.destination)),
+            (
+                "reply",
+                optional(self.reply.map(|rep| format!("{:?}", rep))),
+            ),
+            ("bytes_up", self.bytes.0.to_string()),
+            ("bytes_down", self.bytes.1.to_string()),
+            (
+                "peak_up_bps",
+                self.peak_rate
+                    .map_or_else(|| String::from("null"), |p| p.0.to_string()),
+            ),
+            (
+                "peak_down_bps",
+                self.peak_rate
+                    .map_or_else(|| String::from("null"), |p| p.1.to_string()),
+            ),
+            (
+                "close_reason",
+                optional(self.close_reason.map(|reason| format!("{:?}", reason))),
+            ),
+        ];
+        match result {
+            Ok(()) => fields.push(("result", quote("ok"))),
+            Err(e) => match e.downcast_ref::<RejectReason>() {
+                Some(reason) => fields.push(("result", quote(reason.code()))),
+                None => {
+                    fields.push(("result", quote("error")));
+                    fields.push(("error", quote(&e.to_string())));
+                }
+            },
+        }
+
+        let mut json = String::from("{");
+        for (i, (key, value)) in fields.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            let _ = write!(json, "\"{}\":{}", key, value);
+        }
+        json.push('}');
+
+        info!(target: "socks5_server::record", "{}", json);
+    }
+}
+
+fn millis(d: Duration) -> String {
+    format!("{:.3}", d.as_secs_f64() * 1000.0)
+}
+
+fn optional<T: ToString>(value: Option<T>) -> String {
+    value.map_or_else(|| String::from("null"), |v| quote(&v.to_string()))
+}
+
+fn quote(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -0,0 +1,211 @@
+use std::collections::HashSet;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use tokio::net::lookup_host;
+use tokio::sync::Semaphore;
+
+/// Resolves CONNECT domains through the system resolver, bounding each
+/// query by `timeout` and retrying ones that time out.
+///
+/// A timed-out `getaddrinfo` call keeps running on the blocking pool; the
+/// timeout only stops the connection from waiting for it. With a query
+/// limit, its slot stays taken until it really finishes, so the limit
+/// bounds the calls in flight rather than the connections waiting.
+pub(crate) struct Resolver {
+    timeout: Duration,
+    retries: u32,
+    slots: Option<Arc<Semaphore>>,
+    breaker: Option<Breaker>,
+}
+
+/// Stops sending queries once resolutions of `threshold` different domains
+/// have timed out in a row, failing them straight away instead. Counting
+/// distinct domains keeps one client from tripping it with a single name
+/// whose servers never answer. One query is let through every `cooldown`
+/// as a probe; any answer, even an error, closes it again.
+struct Breaker {
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+}
+
+#[derive(Default)]
+struct BreakerState {
+    /// Domains that have timed out since the last answer.
+    timed_out: HashSet<String>,
+    open_until: Option<Instant>,
+}
+
+impl Breaker {
+    fn allow(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match state.open_until {
+            None => true,
+            Some(until) if Instant::now() < until => false,
+            Some(_) => {
+                state.open_until = Some(Instant::now() + self.cooldown);
+                true
+            }
+        }
+    }
+
+    fn record(&self, host: &str, timed_out: bool) {
+        let mut state = self.state.lock().unwrap();
+        if !timed_out {
+            if state.open_until.take().is_some() {
+                info!("DNS resolver answering again, resuming queries");
+            }
+            state.timed_out.clear();
+            return;
+        }
+
+        if state.timed_out.len() < self.threshold as usize {
+            state.timed_out.insert(host.to_owned());
+        }
+        if state.timed_out.len() >= self.threshold as usize && state.open_until.is_none() {
+            warn!(
+                "DNS resolver unavailable after {} domains timed out, failing fast for {:?}",
+                state.timed_out.len(),
+                self.cooldown
+            );
+            state.open_until = Some(Instant::now() + self.cooldown);
+        }
+    }
+}
+
+impl Resolver {
+    pub fn new(
+        timeout: Duration,
+        retries: u32,
+        max_queries: Option<usize>,
+        breaker: Option<(u32, Duration)>,
+    ) -> Self {
+        Resolver {
+            timeout,
+            retries,
+            slots: max_queries.map(|n| Arc::new(Semaphore::new(n))),
+            breaker: breaker.map(|(threshold, cooldown)| Breaker {
+                threshold,
+                cooldown,
+                state: Mutex::default(),
+            }),
+        }
+    }
+
+    /// Fails with `ResourceBusy` when no query was sent, because the query
+    /// limit stayed full or the breaker is open.
+    pub async fn resolve(&self, host: &str, port: u16) -> io::Result<SocketAddr> {
+        let breaker = match &self.breaker {
+            Some(breaker) => breaker,
+            None => return self.lookup(host, port).await,
+        };
+        if !breaker.allow() {
+            return Err(io::Error::new(
+                io::ErrorKind::ResourceBusy,
+                "resolver unavailable",
+            ));
+        }
+
+        let result = self.lookup(host, port).await;
+        match &result {
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => breaker.record(host, true),
+            // Our own query limit, not the resolver's fault.
+            Err(e) if e.kind() == io::ErrorKind::ResourceBusy => {}
+            _ => breaker.record(host, false),
+        }
+        result
+    }
+
+    async fn lookup(&self, host: &str, port: u16) -> io::Result<SocketAddr> {
+        let mut last_err = None;
+
+        for _ in 0..=self.retries {
+            let permit = match &self.slots {
+                Some(slots) => {
+                    match tokio::time::timeout(self.timeout, slots.clone().acquire_owned()).await {
+                        Ok(permit) => Some(permit.expect("semaphore closed")),
+                        Err(_) => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::ResourceBusy,
+                                "too many DNS queries in flight",
+                            ))
+                        }
+                    }
+                }
+                None => None,
+            };
+
+            let host = host.to_owned();
+            let query = tokio::spawn(async move {
+                let _permit = permit;
+                lookup_host((host.as_str(), port))
+                    .await
+                    .map(|mut addrs| addrs.next())
+            });
+            match tokio::time::timeout(self.timeout, query).await {
+                Ok(Ok(Ok(Some(addr)))) => return Ok(addr),
+                Ok(Ok(Ok(None))) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        "no addresses found",
+                    ))
+                }
+                // An answer, even an error: the system resolver has already
+                // tried its own servers, and asking again would not change it.
+                Ok(Ok(Err(e))) => return Err(e),
+                Ok(Err(e)) => last_err = Some(io::Error::other(e)),
+                Err(_) => {
+                    last_err = Some(io::Error::new(io::ErrorKind::TimedOut, "query timed out"))
+                }
+            }
+        }
+
+        Err(last_err.unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(threshold: u32) -> Breaker {
+        Breaker {
+            threshold,
+            cooldown: Duration::from_secs(60),
+            state: Mutex::default(),
+        }
+    }
+
+    #[test]
+    fn one_domain_cannot_open_it() {
+        let breaker = breaker(2);
+        for _ in 0..10 {
+            breaker.record("blackhole.test", true);
+        }
+        assert!(breaker.allow());
+    }
+
+    #[test]
+    fn distinct_domains_open_it_until_an_answer() {
+        let breaker = breaker(2);
+        breaker.record("a.test", true);
+        breaker.record("b.test", true);
+        assert!(!breaker.allow());
+
+        breaker.record("c.test", false);
+        assert!(breaker.allow());
+    }
+
+    #[test]
+    fn an_answer_resets_the_streak() {
+        let breaker = breaker(2);
+        breaker.record("a.test", true);
+        breaker.record("b.test", false);
+        breaker.record("c.test", true);
+        assert!(breaker.allow());
+    }
+}
@@ -0,0 +1,48 @@
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+
+use crate::DialContext;
+
+/// A CONNECT destination as the client sent it, before resolution.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Destination {
+    Addr(SocketAddr),
+    Domain(String, u16),
+}
+
+impl Destination {
+    pub fn port(&self) -> u16 {
+        match self {
+            Destination::Addr(addr) => addr.port(),
+            Destination::Domain(_, port) => *port,
+        }
+    }
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Destination::Addr(addr) => write!(f, "{}", addr),
+            Destination::Domain(host, port) => write!(f, "{}:{}", host, port),
+        }
+    }
+}
+
+pub type RewriteFuture<'a> = Pin<Box<dyn Future<Output = Destination> + Send + 'a>>;
+
+/// Chooses where a CONNECT request actually goes. Runs after the request
+/// is parsed and before the destination is resolved and dialed.
+pub trait DestinationRewriter: Send + Sync {
+    fn rewrite<'a>(&'a self, dest: &'a Destination, ctx: &'a DialContext) -> RewriteFuture<'a>;
+}
+
+/// Leaves every destination as requested.
+pub struct IdentityRewriter;
+
+impl DestinationRewriter for IdentityRewriter {
+    fn rewrite<'a>(&'a self, dest: &'a Destination, _ctx: &'a DialContext) -> RewriteFuture<'a> {
+        Box::pin(async move { dest.clone() })
+    }
+}
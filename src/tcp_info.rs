@@ -0,0 +1,55 @@
+use std::fmt;
+use std::os::fd::AsRawFd;
+use std::time::Duration;
+
+/// A snapshot of the kernel's `TCP_INFO` for one socket.
+#[derive(Clone, Copy, Debug)]
+pub struct TcpInfo {
+    pub rtt: Duration,
+    pub rtt_var: Duration,
+    /// Segments retransmitted over the life of the connection.
+    pub total_retrans: u32,
+    /// Segments currently considered lost.
+    pub lost: u32,
+    /// Congestion window, in segments.
+    pub snd_cwnd: u32,
+}
+
+impl TcpInfo {
+    pub fn sample(socket: &impl AsRawFd) -> Option<TcpInfo> {
+        // SAFETY: tcp_info is plain old data, and getsockopt writes at most
+        // `len` bytes into it.
+        let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+        let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                socket.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_INFO,
+                &mut info as *mut libc::tcp_info as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if ret != 0 {
+            return None;
+        }
+
+        Some(TcpInfo {
+            rtt: Duration::from_micros(info.tcpi_rtt.into()),
+            rtt_var: Duration::from_micros(info.tcpi_rttvar.into()),
+            total_retrans: info.tcpi_total_retrans,
+            lost: info.tcpi_lost,
+            snd_cwnd: info.tcpi_snd_cwnd,
+        })
+    }
+}
+
+impl fmt::Display for TcpInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "rtt={:?} rttvar={:?} retrans={} lost={} cwnd={}",
+            self.rtt, self.rtt_var, self.total_retrans, self.lost, self.snd_cwnd
+        )
+    }
+}
@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Entries held before stale ones are first pruned.
+const PRUNE_THRESHOLD: usize = 1024;
+/// Entries held at most. Once full, new keys are not tracked until
+/// pruning frees room, so a flood of distinct keys cannot grow the map
+/// without bound.
+const MAX_ENTRIES: usize = 65_536;
+/// How often a full map is pruned, so a map full of live entries doesn't
+/// cost a scan on every failure.
+const FULL_PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Counts failures per key, such as a client IP, and puts a key into
+/// cooldown once it reaches `limit` failures within `window`.
+pub(crate) struct FailureTracker<K> {
+    limit: Option<u32>,
+    window: Duration,
+    cooldown: Duration,
+    entries: Mutex<Entries<K>>,
+}
+
+struct Entries<K> {
+    map: HashMap<K, Entry>,
+    /// Prune again once the map reaches this size: double what was left
+    /// after the last prune, so each prune is paid for by as many inserts.
+    next_prune: usize,
+    pruned_at: Instant,
+}
+
+struct Entry {
+    failures: u32,
+    since: Instant,
+    blocked_until: Option<Instant>,
+}
+
+impl<K: Hash + Eq + Clone> FailureTracker<K> {
+    pub fn new(limit: Option<u32>, window: Duration, cooldown: Duration) -> Self {
+        FailureTracker {
+            limit,
+            window,
+            cooldown,
+            entries: Mutex::new(Entries {
+                map: HashMap::new(),
+                next_prune: PRUNE_THRESHOLD,
+                pruned_at: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn is_blocked(&self, key: &K) -> bool {
+        if self.limit.is_none() {
+            return false;
+        }
+
+        let entries = self.entries.lock().unwrap();
+        matches!(
+            entries.map.get(key).and_then(|e| e.blocked_until),
+            Some(until) if until > Instant::now()
+        )
+    }
+
+    /// Returns true if this failure started a cooldown for `key`.
+    pub fn record(&self, key: K) -> bool {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return false,
+        };
+        let now = Instant::now();
+
+        let mut entries = self.entries.lock().unwrap();
+        let entries = &mut *entries;
+        let full = entries.map.len() >= MAX_ENTRIES;
+        if entries.map.len() >= entries.next_prune
+            && (!full || now - entries.pruned_at >= FULL_PRUNE_INTERVAL)
+        {
+            entries.map.retain(|_, e| !self.is_stale(e, now));
+            entries.next_prune = (entries.map.len() * 2).clamp(PRUNE_THRESHOLD, MAX_ENTRIES);
+            entries.pruned_at = now;
+        }
+        if entries.map.len() >= MAX_ENTRIES && !entries.map.contains_key(&key) {
+            return false;
+        }
+
+        let entry = entries.map.entry(key).or_insert(Entry {
+            failures: 0,
+            since: now,
+            blocked_until: None,
+        });
+        if now - entry.since > self.window {
+            entry.failures = 0;
+            entry.since = now;
+        }
+
+        entry.failures += 1;
+        if entry.failures < limit {
+            return false;
+        }
+
+        entry.failures = 0;
+        entry.since = now;
+        entry.blocked_until = Some(now + self.cooldown);
+        true
+    }
+
+    /// Keys currently in cooldown, with when the cooldown started and ends.
+    pub fn blocked(&self) -> Vec<(K, Instant, Instant)> {
+        let now = Instant::now();
+        let entries = self.entries.lock().unwrap();
+        entries
+            .map
+            .iter()
+            .filter_map(|(key, e)| match e.blocked_until {
+                Some(until) if until > now => Some((key.clone(), e.since, until)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn is_stale(&self, entry: &Entry, now: Instant) -> bool {
+        let blocked = matches!(entry.blocked_until, Some(until) if until > now);
+        !blocked && now - entry.since > self.window
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stops_tracking_new_keys_when_full() {
+        let tracker =
+            FailureTracker::new(Some(1), Duration::from_secs(60), Duration::from_secs(60));
+        for key in 0..MAX_ENTRIES {
+            assert!(tracker.record(key));
+        }
+        assert!(!tracker.record(MAX_ENTRIES));
+        assert!(!tracker.is_blocked(&MAX_ENTRIES));
+        assert!(tracker.record(0));
+    }
+
+    #[test]
+    fn prunes_stale_entries() {
+        let tracker = FailureTracker::new(Some(2), Duration::ZERO, Duration::from_secs(60));
+        for key in 0..PRUNE_THRESHOLD * 4 {
+            tracker.record(key);
+        }
+        assert!(tracker.entries.lock().unwrap().map.len() <= PRUNE_THRESHOLD);
+    }
+}
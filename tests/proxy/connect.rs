@@ -0,0 +1,125 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::support::*;
+use socks5_server::{Config, DialContext, DialFuture, Dialed, Dialer, Extensions};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+#[tokio::test]
+async fn connect_relays_to_upstream() {
+    let upstream = start_upstream(Upstream::Echo).await;
+    let proxy = start_proxy(Config::default()).await;
+
+    let mut client = greeted(proxy).await;
+    client.write_all(&connect_request(upstream)).await.unwrap();
+    assert_eq!(read_n(&mut client, 10).await[..2], [0x05, 0x00]);
+
+    client.write_all(b"hello").await.unwrap();
+    assert_eq!(read_n(&mut client, 5).await, b"hello");
+    client.shutdown().await.unwrap();
+    assert!(read_to_eof(&mut client).await.is_empty());
+}
+
+#[tokio::test]
+async fn pipelined_payload_reaches_upstream() {
+    let upstream = start_upstream(Upstream::Echo).await;
+    let proxy = start_proxy(Config::default()).await;
+
+    // Greeting, CONNECT and the first request in a single write, without
+    // waiting for either reply.
+    let get = b"GET / HTTP/1.1\r\nHost: example.test\r\n\r\n";
+    let mut burst = vec![0x05, 0x01, 0x00];
+    burst.extend_from_slice(&connect_request(upstream));
+    burst.extend_from_slice(get);
+
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    client.write_all(&burst).await.unwrap();
+    assert_eq!(read_n(&mut client, 2).await, [0x05, 0x00]);
+    assert_eq!(read_n(&mut client, 10).await[..2], [0x05, 0x00]);
+    assert_eq!(read_n(&mut client, get.len()).await, get);
+}
+
+#[tokio::test]
+async fn bound_addr_is_opt_in() {
+    let upstream = start_upstream(Upstream::Sink).await;
+
+    let proxy = start_proxy(Config::default()).await;
+    let mut client = greeted(proxy).await;
+    client.write_all(&connect_request(upstream)).await.unwrap();
+    assert_eq!(
+        read_n(&mut client, 10).await,
+        [0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]
+    );
+
+    let config = Config {
+        report_bound_addr: true,
+        ..Config::default()
+    };
+    let proxy = start_proxy(config).await;
+    let mut client = greeted(proxy).await;
+    client.write_all(&connect_request(upstream)).await.unwrap();
+    let reply = read_n(&mut client, 10).await;
+    assert_eq!(reply[..8], [0x05, 0x00, 0x00, 0x01, 127, 0, 0, 1]);
+    assert_ne!(reply[8..], [0, 0]);
+}
+
+#[tokio::test]
+async fn classified_tunnel_relays_unchanged() {
+    let upstream = start_upstream(Upstream::Echo).await;
+    let config = Config {
+        classify_protocols: true,
+        ..Config::default()
+    };
+    let proxy = start_proxy(config).await;
+
+    let mut client = greeted(proxy).await;
+    client.write_all(&connect_request(upstream)).await.unwrap();
+    assert_eq!(read_n(&mut client, 10).await[..2], [0x05, 0x00]);
+
+    // The classifier peeks at this; all of it must still reach upstream.
+    let get = b"GET / HTTP/1.1\r\nHost: example.test\r\n\r\n";
+    client.write_all(get).await.unwrap();
+    assert_eq!(read_n(&mut client, get.len()).await, get);
+    client.shutdown().await.unwrap();
+    assert!(read_to_eof(&mut client).await.is_empty());
+}
+
+/// Reaches every destination over an in-memory pipe to an echo task.
+struct PipeDialer;
+
+impl Dialer for PipeDialer {
+    fn dial<'a>(&'a self, _addr: SocketAddr, _ctx: &'a DialContext) -> DialFuture<'a> {
+        Box::pin(async move {
+            let (near, mut far) = tokio::io::duplex(1024);
+            tokio::spawn(async move {
+                let (mut read, mut write) = tokio::io::split(&mut far);
+                let _ = tokio::io::copy(&mut read, &mut write).await;
+            });
+            Ok(Dialed::new(near))
+        })
+    }
+}
+
+#[tokio::test]
+async fn custom_transport_without_local_addr() {
+    let config = Config {
+        report_bound_addr: true,
+        ..Config::default()
+    };
+    let extensions = Extensions {
+        dialer: Some(Arc::new(PipeDialer)),
+        ..Extensions::default()
+    };
+    let proxy = start_proxy_with(config, extensions).await;
+
+    let mut client = greeted(proxy).await;
+    let dest = "192.0.2.1:80".parse().unwrap();
+    client.write_all(&connect_request(dest)).await.unwrap();
+    assert_eq!(
+        read_n(&mut client, 10).await,
+        [0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]
+    );
+    client.write_all(b"hello").await.unwrap();
+    assert_eq!(read_n(&mut client, 5).await, b"hello");
+}
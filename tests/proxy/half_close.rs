@@ -0,0 +1,73 @@
+//! Each direction of a tunnel closes on its own: after one side shuts down
+//! its write half, the other direction keeps flowing until it is shut
+//! down too.
+
+use std::time::Duration;
+
+use crate::support::*;
+use socks5_server::Config;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+
+/// Runs the CONNECT handshake to a one-shot upstream running `upstream`,
+/// returning the client side of the tunnel.
+async fn tunnel<F, Fut>(upstream: F) -> tokio::net::TcpStream
+where
+    F: FnOnce(tokio::net::TcpStream) -> Fut + Send + 'static,
+    Fut: std::future::Future<Output = ()> + Send,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let dest = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        upstream(stream).await;
+    });
+
+    let proxy = start_proxy(Config::default()).await;
+    let mut client = greeted(proxy).await;
+    client.write_all(&connect_request(dest)).await.unwrap();
+    assert_eq!(read_n(&mut client, 10).await[..2], [0x05, 0x00]);
+    client
+}
+
+#[tokio::test]
+async fn client_closes_first() {
+    let (done, received) = oneshot::channel();
+    let mut client = tunnel(|mut upstream| async move {
+        let mut request = Vec::new();
+        upstream.read_to_end(&mut request).await.unwrap();
+        // Answer only after the client's half-close has come through.
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        upstream.write_all(b"response").await.unwrap();
+        upstream.shutdown().await.unwrap();
+        done.send(request).unwrap();
+    })
+    .await;
+
+    client.write_all(b"request").await.unwrap();
+    client.shutdown().await.unwrap();
+    assert_eq!(read_to_eof(&mut client).await, b"response");
+    assert_eq!(received.await.unwrap(), b"request");
+}
+
+#[tokio::test]
+async fn upstream_closes_first() {
+    let (done, received) = oneshot::channel();
+    let mut client = tunnel(|mut upstream| async move {
+        upstream.write_all(b"banner").await.unwrap();
+        upstream.shutdown().await.unwrap();
+        let mut request = Vec::new();
+        upstream.read_to_end(&mut request).await.unwrap();
+        done.send(request).unwrap();
+    })
+    .await;
+
+    assert_eq!(read_to_eof(&mut client).await, b"banner");
+    // The client can still send after the upstream has finished.
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    client.write_all(b"request").await.unwrap();
+    client.shutdown().await.unwrap();
+    let received = tokio::time::timeout(TIMEOUT, received).await;
+    assert_eq!(received.unwrap().unwrap(), b"request");
+}
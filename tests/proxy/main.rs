@@ -0,0 +1,9 @@
+//! End-to-end tests against a proxy on an ephemeral loopback port. One
+//! binary, so a helper in `support` nobody uses is flagged as dead code.
+
+mod support;
+
+mod connect;
+mod half_close;
+mod replies;
+mod throttle;
@@ -0,0 +1,109 @@
+//! Every handshake error gets exactly one reply, framed for the stage it
+//! happened in, and then the connection is closed.
+
+use std::time::Duration;
+
+use crate::support::*;
+use socks5_server::Config;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+const NO_METHOD: [u8; 2] = [0x05, 0xff];
+
+fn command_reply(rep: u8) -> [u8; 10] {
+    [0x05, rep, 0x00, 0x01, 0, 0, 0, 0, 0, 0]
+}
+
+#[tokio::test]
+async fn bad_version() {
+    let proxy = start_proxy(Config::default()).await;
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    assert_reply_then_eof(&mut client, &[0x06], &NO_METHOD).await;
+}
+
+#[tokio::test]
+async fn no_acceptable_method() {
+    let proxy = start_proxy(Config::default()).await;
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    assert_reply_then_eof(&mut client, &[0x05, 0x01, 0x80], &NO_METHOD).await;
+}
+
+#[tokio::test]
+async fn auth_failure() {
+    let proxy = start_proxy(Config::default()).await;
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    client.write_all(&[0x05, 0x01, 0x02]).await.unwrap();
+    assert_eq!(read_n(&mut client, 2).await, [0x05, 0x02]);
+
+    let auth = [0x01, 0x03, b'b', b'a', b'd', 0x03, b'b', b'a', b'd'];
+    assert_reply_then_eof(&mut client, &auth, &[0x01, 0x01]).await;
+}
+
+#[tokio::test]
+async fn unsupported_command() {
+    let proxy = start_proxy(Config::default()).await;
+    let mut client = greeted(proxy).await;
+    // BIND; the address is never read.
+    assert_reply_then_eof(&mut client, &[0x05, 0x02, 0x00], &command_reply(0x07)).await;
+}
+
+#[tokio::test]
+async fn unsupported_address_type() {
+    let proxy = start_proxy(Config::default()).await;
+    let mut client = greeted(proxy).await;
+    let request = [0x05, 0x01, 0x00, 0x05];
+    assert_reply_then_eof(&mut client, &request, &command_reply(0x08)).await;
+}
+
+#[tokio::test]
+async fn dns_failure() {
+    let config = Config {
+        dns_timeout: Duration::from_secs(2),
+        ..Config::default()
+    };
+    let proxy = start_proxy(config).await;
+    let mut client = greeted(proxy).await;
+    let request = connect_domain_request("nonexistent.invalid", 80);
+    assert_reply_then_eof(&mut client, &request, &command_reply(0x04)).await;
+}
+
+#[tokio::test]
+async fn dial_failure() {
+    let dest = closed_port().await;
+    let proxy = start_proxy(Config::default()).await;
+    let mut client = greeted(proxy).await;
+    assert_reply_then_eof(&mut client, &connect_request(dest), &command_reply(0x05)).await;
+}
+
+#[tokio::test]
+async fn auth_before_method() {
+    let proxy = start_proxy(Config::default()).await;
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    // A username/password subnegotiation sent in place of the greeting.
+    let auth = [0x01, 0x03, b'1', b'2', b'3', 0x03, b'1', b'2', b'3'];
+    client.write_all(&auth).await.unwrap();
+    assert_eq!(read_n(&mut client, 2).await, NO_METHOD);
+    assert_closed(&mut client).await;
+}
+
+#[tokio::test]
+async fn port_zero_refused_before_lookup() {
+    let proxy = start_proxy(Config::default()).await;
+    let mut client = greeted(proxy).await;
+    // Would be HostUnreached if the name were looked up first.
+    let request = connect_domain_request("nonexistent.invalid", 0);
+    assert_reply_then_eof(&mut client, &request, &command_reply(0x02)).await;
+}
+
+#[tokio::test]
+async fn command_timeout_covers_the_address() {
+    let config = Config {
+        command_timeout: Some(Duration::from_millis(200)),
+        ..Config::default()
+    };
+    let proxy = start_proxy(config).await;
+    let mut client = greeted(proxy).await;
+    // The header arrives in time; the address never does.
+    client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+    assert_closed(&mut client).await;
+}
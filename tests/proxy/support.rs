@@ -0,0 +1,133 @@
+//! Servers and client helpers shared by the integration tests.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use socks5_server::{Config, Extensions};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Long enough for anything on loopback; a test that waits this long has hung.
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Starts the proxy on an ephemeral loopback port.
+pub async fn start_proxy(config: Config) -> SocketAddr {
+    start_proxy_with(config, Extensions::default()).await
+}
+
+pub async fn start_proxy_with(config: Config, extensions: Extensions) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(socks5_server::serve_listener(listener, config, extensions));
+    addr
+}
+
+/// What a test upstream does with each connection it accepts.
+#[derive(Clone)]
+pub enum Upstream {
+    /// Writes back everything it reads, then shuts down its write half
+    /// once the client has.
+    Echo,
+    /// Reads and discards everything.
+    Sink,
+}
+
+/// Starts a test upstream on an ephemeral loopback port.
+pub async fn start_upstream(behavior: Upstream) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(serve_upstream(stream, behavior.clone()));
+        }
+    });
+    addr
+}
+
+async fn serve_upstream(mut stream: TcpStream, behavior: Upstream) {
+    match behavior {
+        Upstream::Echo => {
+            let (mut read, mut write) = stream.split();
+            let _ = tokio::io::copy(&mut read, &mut write).await;
+            let _ = write.shutdown().await;
+        }
+        Upstream::Sink => {
+            let _ = tokio::io::copy(&mut stream, &mut tokio::io::sink()).await;
+        }
+    }
+}
+
+/// An address nothing is listening on, so connecting to it is refused.
+pub async fn closed_port() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    listener.local_addr().unwrap()
+}
+
+/// A CONNECT request for an IPv4 destination.
+pub fn connect_request(dest: SocketAddr) -> Vec<u8> {
+    let ip = match dest {
+        SocketAddr::V4(addr) => addr.ip().octets(),
+        SocketAddr::V6(_) => panic!("connect_request takes IPv4 destinations"),
+    };
+    let mut request = vec![0x05, 0x01, 0x00, 0x01];
+    request.extend_from_slice(&ip);
+    request.extend_from_slice(&dest.port().to_be_bytes());
+    request
+}
+
+/// A CONNECT request for a domain destination.
+pub fn connect_domain_request(host: &str, port: u16) -> Vec<u8> {
+    let mut request = vec![0x05, 0x01, 0x00, 0x03, host.len() as u8];
+    request.extend_from_slice(host.as_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+    request
+}
+
+/// Connects to the proxy and completes a no-auth greeting.
+pub async fn greeted(proxy: SocketAddr) -> TcpStream {
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    stream.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+    assert_eq!(read_n(&mut stream, 2).await, [0x05, 0x00]);
+    stream
+}
+
+/// Reads exactly `n` bytes.
+pub async fn read_n(stream: &mut TcpStream, n: usize) -> Vec<u8> {
+    let mut buf = vec![0u8; n];
+    tokio::time::timeout(TIMEOUT, stream.read_exact(&mut buf))
+        .await
+        .expect("timed out reading")
+        .expect("read failed");
+    buf
+}
+
+/// Reads until EOF and returns everything read.
+pub async fn read_to_eof(stream: &mut TcpStream) -> Vec<u8> {
+    let mut buf = Vec::new();
+    tokio::time::timeout(TIMEOUT, stream.read_to_end(&mut buf))
+        .await
+        .expect("timed out waiting for EOF")
+        .expect("read failed");
+    buf
+}
+
+/// Waits for the server to close. A reset counts too: closing with unread
+/// data from us still in its buffer makes the kernel send one.
+pub async fn assert_closed(stream: &mut TcpStream) {
+    let mut buf = [0u8; 64];
+    let read = tokio::time::timeout(TIMEOUT, stream.read(&mut buf))
+        .await
+        .expect("timed out waiting for close");
+    match read {
+        Ok(0) => {}
+        Ok(n) => panic!("expected close, got {:02x?}", &buf[..n]),
+        Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::ConnectionReset),
+    }
+}
+
+/// Sends `request`, then checks the server answers with exactly `reply`
+/// and closes.
+pub async fn assert_reply_then_eof(stream: &mut TcpStream, request: &[u8], reply: &[u8]) {
+    stream.write_all(request).await.unwrap();
+    assert_eq!(read_to_eof(stream).await, reply);
+}
@@ -0,0 +1,83 @@
+use crate::support::*;
+use socks5_server::Config;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+fn throttled() -> Config {
+    Config {
+        handshake_failure_limit: Some(1),
+        ..Config::default()
+    }
+}
+
+#[tokio::test]
+async fn protocol_errors_are_throttled() {
+    let proxy = start_proxy(throttled()).await;
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    assert_reply_then_eof(&mut client, &[0x06], &[0x05, 0xff]).await;
+
+    // Dropped at accept, without a reply.
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    assert!(read_to_eof(&mut client).await.is_empty());
+}
+
+#[tokio::test]
+async fn each_wrong_password_is_counted() {
+    let config = Config {
+        max_auth_attempts: 3,
+        ..throttled()
+    };
+    let proxy = start_proxy(config).await;
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    client.write_all(&[0x05, 0x01, 0x02]).await.unwrap();
+    assert_eq!(read_n(&mut client, 2).await, [0x05, 0x02]);
+    let auth = [0x01, 0x03, b'b', b'a', b'd', 0x03, b'b', b'a', b'd'];
+    client.write_all(&auth).await.unwrap();
+    assert_eq!(read_n(&mut client, 2).await, [0x01, 0x01]);
+    // Hang up instead of using the remaining attempts.
+    drop(client);
+
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    assert!(read_to_eof(&mut client).await.is_empty());
+}
+
+#[tokio::test]
+async fn refused_connects_are_not_throttled() {
+    let dest = closed_port().await;
+    let proxy = start_proxy(throttled()).await;
+    for _ in 0..3 {
+        let mut client = greeted(proxy).await;
+        let reply = [0x05, 0x05, 0x00, 0x01, 0, 0, 0, 0, 0, 0];
+        assert_reply_then_eof(&mut client, &connect_request(dest), &reply).await;
+    }
+
+    let mut client = greeted(proxy).await;
+    let reply = [0x05, 0x02, 0x00, 0x01, 0, 0, 0, 0, 0, 0];
+    let port_zero = connect_request("127.0.0.1:0".parse().unwrap());
+    assert_reply_then_eof(&mut client, &port_zero, &reply).await;
+    greeted(proxy).await;
+}
+
+#[tokio::test]
+async fn tarpit_is_bounded() {
+    let config = Config {
+        tarpit: Some(std::time::Duration::from_secs(30)),
+        max_tarpitted: 1,
+        ..throttled()
+    };
+    let proxy = start_proxy(config).await;
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    assert_reply_then_eof(&mut client, &[0x06], &[0x05, 0xff]).await;
+
+    // The first connection in cooldown is held open...
+    let mut held = TcpStream::connect(proxy).await.unwrap();
+    let wait = std::time::Duration::from_millis(300);
+    let mut buf = [0u8; 1];
+    assert!(tokio::time::timeout(wait, held.read(&mut buf))
+        .await
+        .is_err());
+
+    // ...and once the tarpit is full the next is closed straight away.
+    let mut closed = TcpStream::connect(proxy).await.unwrap();
+    assert_closed(&mut closed).await;
+}